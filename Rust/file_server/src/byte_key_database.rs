@@ -0,0 +1,310 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use crate::database::{delete_file, load_files, save_file, unix_time_ms, File, Metadata, Modification, ScanOptions};
+
+// key file name is hex encoded key, so key length is limited by maximum file name length
+pub const MAX_BYTE_KEY_LENGTH: usize = 127;
+
+#[derive(Clone)]
+pub struct ByteKeyValue {
+    pub key: Vec<u8>,
+    pub version: u32,
+    pub value: Vec<u8>,
+}
+
+// database with byte string keys ordered lexicographically
+pub struct ByteKeyDatabase {
+    base_folder: PathBuf,
+    version: u32,
+    data: BTreeMap<Vec<u8>, File>
+}
+
+impl ByteKeyValue {
+    // count: u32, [key length: u8, key, value length: u32, value]
+    pub fn from(data: &[u8]) -> Result<Vec<ByteKeyValue>, Error> {
+        let l = data.len();
+        if l < 4 {
+            return Err(Error::new(ErrorKind::InvalidInput, "data is too short"));
+        }
+        let mut length = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let mut idx = 4;
+        let mut result = Vec::new();
+        while length > 0 {
+            let (key, key_length) = read_byte_key(&data[idx..])?;
+            idx += key_length;
+            if l < idx + 4 {
+                return Err(Error::new(ErrorKind::InvalidInput, "data is too short"));
+            }
+            let value_length = u32::from_le_bytes(data[idx..idx + 4].try_into().unwrap()) as usize;
+            idx += 4;
+            if l < idx + value_length {
+                return Err(Error::new(ErrorKind::InvalidInput, "data is too short"));
+            }
+            let value = Vec::from(&data[idx..idx + value_length]);
+            idx += value_length;
+            length -= 1;
+            result.push(ByteKeyValue{key, version: 0, value});
+        }
+        if idx != l {
+            return Err(Error::new(ErrorKind::InvalidInput, "incorrect data size"));
+        }
+        Ok(result)
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.extend_from_slice(&self.version.to_le_bytes());
+        write_byte_key(&mut result, &self.key);
+        result.extend_from_slice(&(self.value.len() as u32).to_le_bytes());
+        result.extend_from_slice(&self.value);
+        result
+    }
+}
+
+impl ByteKeyDatabase {
+    pub fn new(base_folder: PathBuf) -> Result<ByteKeyDatabase, Error> {
+        let data = load_data(&base_folder)?;
+        Ok(ByteKeyDatabase{base_folder, data, version: 1})
+    }
+
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    // database folder was renamed
+    pub fn set_base_folder(&mut self, base_folder: PathBuf) {
+        self.base_folder = base_folder;
+    }
+
+    pub fn get_keys(&self, keys: Vec<Vec<u8>>) -> Vec<Option<ByteKeyValue>> {
+        keys.into_iter()
+            .map(|key|self.data.get(&key).map(|f|to_key_value(&key, f)))
+            .collect()
+    }
+
+    pub fn get_prefix(&self, prefix: &[u8], options: &ScanOptions) -> Vec<ByteKeyValue> {
+        let end = match prefix_end(prefix) {
+            Some(end) => Excluded(end),
+            None => Unbounded
+        };
+        self.scan((Included(prefix.to_vec()), end), options)
+    }
+
+    pub fn get_range(&self, key1: &[u8], key2: &[u8], options: &ScanOptions) -> Vec<ByteKeyValue> {
+        if key1 > key2 {
+            return Vec::new();
+        }
+        self.scan(key1.to_vec()..=key2.to_vec(), options)
+    }
+
+    // empty value deletes the key, keys changed before a file write error are reverted
+    pub fn set(&mut self, expected_version: u32, data: Vec<ByteKeyValue>, user_id: u32) -> Result<(), Error> {
+        if expected_version != self.version {
+            return Err(Error::new(ErrorKind::InvalidData, "version mismatch"));
+        }
+        // previous files of changed keys
+        let mut changes = Vec::new();
+        let modified = Modification{time: unix_time_ms(), user_id};
+        for kv in data {
+            let file = if !kv.value.is_empty() {
+                let version = self.data.get(&kv.key).map(|f| f.version).unwrap_or(0) + 1;
+                Some(File{version, data: kv.value, expires_at: None, modified, metadata: Metadata::new()})
+            } else {
+                None
+            };
+            match self.replace(&kv.key, file) {
+                Ok(old) => changes.push((kv.key, old)),
+                Err(e) => {
+                    for (key, file) in changes.into_iter().rev() {
+                        self.replace(&key, file)?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        self.version += 1;
+        Ok(())
+    }
+
+    // saves or deletes (file = None) key file, returns previous file
+    fn replace(&mut self, key: &[u8], file: Option<File>) -> Result<Option<File>, Error> {
+        match file {
+            Some(file) => {
+                self.save(key, &file)?;
+                Ok(self.data.insert(key.to_vec(), file))
+            }
+            None => {
+                self.delete(key)?;
+                Ok(self.data.remove(key))
+            }
+        }
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, options: &ScanOptions) -> Vec<ByteKeyValue> {
+        let range = self.data.range(range);
+        let items: Vec<(&Vec<u8>, &File)> = if options.descending {
+            range.rev().step_by(options.step).take(options.limit).collect()
+        } else {
+            range.step_by(options.step).take(options.limit).collect()
+        };
+        items.into_iter()
+            .map(|(k, f)|to_key_value(k, f))
+            .collect()
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        delete_file(&self.build_folder_path(key), &to_hex(key))
+    }
+
+    fn save(&self, key: &[u8], file: &File) -> Result<(), Error> {
+        save_file(&self.build_folder_path(key), &to_hex(key), file)
+    }
+
+    // folder name is hex encoded first key byte, file name is hex encoded key
+    fn build_folder_path(&self, key: &[u8]) -> PathBuf {
+        self.base_folder.join(to_hex(&key[0..1]))
+    }
+}
+
+// key length: u8, key
+pub fn read_byte_key(data: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+    if data.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "data is too short"));
+    }
+    let length = data[0] as usize;
+    if length == 0 || length > MAX_BYTE_KEY_LENGTH {
+        return Err(Error::new(ErrorKind::InvalidInput, "invalid key length"));
+    }
+    if data.len() < length + 1 {
+        return Err(Error::new(ErrorKind::InvalidInput, "data is too short"));
+    }
+    Ok((data[1..length+1].to_vec(), length + 1))
+}
+
+pub fn write_byte_key(data: &mut Vec<u8>, key: &[u8]) {
+    data.push(key.len() as u8);
+    data.extend_from_slice(key);
+}
+
+// (u64, u64) composite key, big endian byte order keeps lexicographic order the same as numeric one
+pub fn wide_composite_key(first: u64, second: u64) -> Vec<u8> {
+    let mut result = first.to_be_bytes().to_vec();
+    result.extend_from_slice(&second.to_be_bytes());
+    result
+}
+
+pub fn split_wide_composite_key(key: &[u8]) -> Option<(u64, u64)> {
+    if key.len() != 16 {
+        return None;
+    }
+    Some((u64::from_be_bytes(key[0..8].try_into().unwrap()), u64::from_be_bytes(key[8..16].try_into().unwrap())))
+}
+
+// smallest key that is greater than all keys with given prefix, None when there is no such key
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last != 0xFF {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+fn to_key_value(key: &[u8], file: &File) -> ByteKeyValue {
+    ByteKeyValue{key: key.to_vec(), version: file.version, value: file.data.clone()}
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b|format!("{:02x}", b)).collect()
+}
+
+fn from_hex(name: &str) -> Result<Vec<u8>, Error> {
+    if name.is_empty() || !name.is_ascii() || !name.len().is_multiple_of(2) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("invalid key file name {}", name)));
+    }
+    (0..name.len()).step_by(2)
+        .map(|i|u8::from_str_radix(&name[i..i+2], 16)
+            .map_err(|e|Error::new(ErrorKind::InvalidData, e)))
+        .collect()
+}
+
+fn load_data(base_folder: &Path) -> Result<BTreeMap<Vec<u8>, File>, Error> {
+    let mut result = BTreeMap::new();
+    load_files(base_folder, |_, name, _, file|{
+        result.insert(from_hex(name)?, file);
+        Ok(())
+    })?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Error;
+    use std::path::PathBuf;
+    use crate::byte_key_database::{prefix_end, split_wide_composite_key, wide_composite_key, ByteKeyDatabase, ByteKeyValue};
+    use crate::database::ScanOptions;
+
+    fn kv(key: &str, value: &str) -> ByteKeyValue {
+        ByteKeyValue{key: key.as_bytes().to_vec(), version: 0, value: value.as_bytes().to_vec()}
+    }
+
+    fn keys(data: Vec<ByteKeyValue>) -> Vec<String> {
+        data.into_iter().map(|kv|String::from_utf8(kv.key).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_byte_key_database() -> Result<(), Error> {
+        let path = PathBuf::from("/mnt/ramdisk/test_byte_key_database");
+        fs::create_dir(&path)?;
+        let mut database = ByteKeyDatabase::new(path.clone())?;
+        database.set(1, vec![kv("device/42/config", "c42"), kv("device/42/state", "s42"),
+                             kv("device/5/config", "c5"), kv("user/1", "u1"), kv("a/../\0", "x")], 1)?;
+        assert!(database.set(1, vec![kv("user/2", "u2")], 1).is_err());
+
+        let result = database.get_keys(vec![b"user/1".to_vec(), b"user/2".to_vec()]);
+        assert_eq!(result[0].as_ref().unwrap().value, b"u1");
+        assert!(result[1].is_none());
+
+        let options = ScanOptions::default();
+        assert_eq!(keys(database.get_prefix(b"device/42/", &options)), vec!["device/42/config", "device/42/state"]);
+        assert_eq!(keys(database.get_prefix(b"device/", &ScanOptions{descending: true, step: 1, limit: 2})),
+                   vec!["device/5/config", "device/42/state"]);
+        assert_eq!(keys(database.get_range(b"device/42/state", b"user/1", &options)),
+                   vec!["device/42/state", "device/5/config", "user/1"]);
+        assert_eq!(keys(database.get_prefix(b"device/4", &ScanOptions{descending: true, step: 1, limit: 1})),
+                   vec!["device/42/state"]);
+        assert_eq!(prefix_end(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff"), None);
+
+        database.set(2, vec![kv("user/1", "")], 1)?;
+        let mut database = ByteKeyDatabase::new(path.clone())?;
+        assert_eq!(keys(database.get_prefix(b"", &options)),
+                   vec!["a/../\0", "device/42/config", "device/42/state", "device/5/config"]);
+        assert_eq!(database.get_keys(vec![b"a/../\0".to_vec()])[0].as_ref().unwrap().value, b"x");
+
+        // "z" key folder is a file, so its change fails and other changes are reverted
+        fs::write(path.join("7a"), [0])?;
+        assert!(database.set(1, vec![kv("device/5/config", "new"), kv("a/../\0", ""), kv("z", "z")], 1).is_err());
+        assert_eq!(database.get_version(), 1);
+        for db in [&database, &ByteKeyDatabase::new(path.clone())?] {
+            assert_eq!(keys(db.get_prefix(b"", &options)),
+                       vec!["a/../\0", "device/42/config", "device/42/state", "device/5/config"]);
+            assert_eq!(db.get_keys(vec![b"device/5/config".to_vec()])[0].as_ref().unwrap().value, b"c5");
+        }
+
+        assert!(wide_composite_key(1, 256) > wide_composite_key(1, 2));
+        assert!(wide_composite_key(2, 0) > wide_composite_key(1, u64::MAX));
+        assert_eq!(split_wide_composite_key(&wide_composite_key(7, 1 << 40)), Some((7, 1 << 40)));
+        fs::remove_dir_all(path)
+    }
+}
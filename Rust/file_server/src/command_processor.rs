@@ -1,13 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{Error, ErrorKind};
-use std::sync::RwLock;
+use std::sync::Arc;
+use std::time::Duration;
 use smart_home_common::user_message_processor::CommandProcessor;
-use crate::database::KeyValue;
-use crate::databases::Databases;
+use crate::byte_key_database::{read_byte_key, split_wide_composite_key, wide_composite_key, ByteKeyValue};
+use crate::database::{composite_key, encode_metadata, key_length, read_key, split_composite_key, write_key, KeyValue,
+                      Modification, Navigation, ScanOptions};
+use crate::databases::{start_expiration_sweeper, DatabaseSettings, Databases, TransactionGroup};
 use crate::UserWithKey;
 
+const WIDE_KEYS_FLAG: u8 = 0x80;
+
 pub struct UserCommandProcessor {
-    data: RwLock<Databases>,
+    data: Arc<Databases>,
     user_map: HashMap<u32, UserWithKey>,
 }
 
@@ -27,71 +32,316 @@ impl CommandProcessor for UserCommandProcessor {
         length > 6
     }
 
+    // command id bit 7 set - keys are sent as u64, otherwise as u32
     fn execute(&self, command: Vec<u8>, message_prefix: &[u8]) -> Result<Vec<u8>, Error> {
         let user_id = u32::from_le_bytes(message_prefix.try_into().unwrap());
         let user = self.user_map.get(&user_id).unwrap();
-        match command[0] {
-            0 => self.run_get_command(user, &command[1..]),
-            1 => self.run_set_command(user, &command[1..]),
-            2 => self.run_get_last_command(user, &command[1..]),
-            3 => self.run_get_file_version_command(user, &command[1..]),
+        let w = command[0] & WIDE_KEYS_FLAG != 0;
+        match command[0] & !WIDE_KEYS_FLAG {
+            0 => self.run_get_command(user, &command[1..], w),
+            1 => self.run_set_command(user, &command[1..], w),
+            2 => self.run_get_last_command(user, &command[1..], w),
+            3 => self.run_get_file_version_command(user, &command[1..], w),
+            4 => self.run_get_keys_command(user, &command[1..], w),
+            5 => self.run_get_changed_keys_command(user, &command[1..], w),
+            6 => self.run_get_changed_range_command(user, &command[1..], w),
+            7 => self.run_get_key_versions_command(user, &command[1..], w),
+            8 => self.run_get_range_statistics_command(user, &command[1..], w),
+            9 => self.run_get_first_command(user, &command[1..], w),
+            10 => self.run_navigate_command(user, &command[1..], w, Navigation::Floor),
+            11 => self.run_navigate_command(user, &command[1..], w, Navigation::Ceiling),
+            12 => self.run_navigate_command(user, &command[1..], w, Navigation::Next),
+            13 => self.run_navigate_command(user, &command[1..], w, Navigation::Previous),
+            14 => self.run_append_command(user, &command[1..], w),
+            15 => self.run_pop_command(user, &command[1..], w),
+            16 => self.run_ack_command(user, &command[1..], w, false),
+            17 => self.run_ack_command(user, &command[1..], w, true),
+            18 => self.run_increment_command(user, &command[1..], w),
+            19 => self.run_write_value_command(user, &command[1..], w, false),
+            20 => self.run_write_value_command(user, &command[1..], w, true),
+            21 => self.run_get_value_part_command(user, &command[1..], w),
+            22 => self.run_patch_command(user, &command[1..], w),
+            23 => self.run_set_with_ttl_command(user, &command[1..], w),
+            24 => self.run_get_modified_since_command(user, &command[1..], w),
+            25 => self.run_set_with_metadata_command(user, &command[1..], w),
+            26 => self.run_get_metadata_command(user, &command[1..], w),
+            27 => self.run_find_by_attribute_command(user, &command[1..], w),
+            28 => self.run_get_byte_keys_command(user, &command[1..]),
+            29 => self.run_get_byte_key_prefix_command(user, &command[1..]),
+            30 => self.run_get_byte_key_range_command(user, &command[1..]),
+            31 => self.run_set_byte_keys_command(user, &command[1..]),
+            32 => self.run_composite_get_command(user, &command[1..], w, false, false),
+            33 => self.run_composite_get_command(user, &command[1..], w, true, false),
+            34 => self.run_composite_set_command(user, &command[1..], w, false),
+            35 => self.run_list_databases_command(user),
+            36 => self.run_get_statistics_command(user, &command[1..], w),
+            37 => self.run_create_database_command(user, &command[1..]),
+            38 => self.run_drop_database_command(user, &command[1..]),
+            39 => self.run_clone_database_command(user, &command[1..]),
+            40 => self.run_rename_database_command(user, &command[1..]),
+            41 => self.run_swap_databases_command(user, &command[1..]),
+            42 => self.run_copy_range_command(user, &command[1..], w),
+            43 => self.run_transaction_command(user, &command[1..], w),
+            44 => self.run_composite_get_command(user, &command[1..], true, false, true),
+            45 => self.run_composite_get_command(user, &command[1..], true, true, true),
+            46 => self.run_composite_set_command(user, &command[1..], true, true),
             _ => Err(Error::new(ErrorKind::InvalidInput, "Invalid command"))
         }
     }
 }
 
 impl UserCommandProcessor {
-    pub fn new(base_folder: String, hash_divider: usize, user_map: HashMap<u32, UserWithKey>)
+    pub fn new(base_folder: String, hash_divider: u64, byte_key_databases: HashSet<String>,
+               implicit_database_creation: bool, user_map: HashMap<u32, UserWithKey>)
         -> Result<Box<UserCommandProcessor>, Error> {
-        let data = RwLock::new(Databases::new(base_folder, hash_divider)?);
+        let data = Arc::new(Databases::new(base_folder, hash_divider, byte_key_databases,
+                                           implicit_database_creation)?);
+        start_expiration_sweeper(data.clone());
         Ok(Box::new(UserCommandProcessor{ data, user_map }))
     }
 
-    fn run_get_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
-        let (database, from, to) = parse_get_command_parameters(command)?;
+    fn run_get_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, from, to, options, with_modification) = parse_get_range_command_parameters(command, wide_keys)?;
 
         user.validate_access(&database, true)?;
         
-        let lock = self.data.read().unwrap();
-        let (version, result) = lock.get(database, from, to);
-        
+        let (version, result) = self.data.get(database, from, to, &options)?;
+        Ok(build_get_response(version, result, with_modification, wide_keys))
+    }
+
+    fn run_get_modified_since_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+        let kl = key_length(wide_keys);
+        if idx + kl * 2 + 8 != command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid get modified since command length"));
+        }
+        let from = read_key(&command[idx..], wide_keys);
+        let to = read_key(&command[idx+kl..], wide_keys);
+        let since = u64::from_le_bytes(command[idx+kl*2..idx+kl*2+8].try_into().unwrap());
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.get_modified_since(database, from, to, since)?;
+        Ok(build_get_response(version, result, true, wide_keys))
+    }
+
+    // flags: bit 0 - return values
+    fn run_get_metadata_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+        let kl = key_length(wide_keys);
+        if idx + kl * 2 + 1 != command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid get metadata command length"));
+        }
+        let from = read_key(&command[idx..], wide_keys);
+        let to = read_key(&command[idx+kl..], wide_keys);
+        let with_value = command[idx+kl*2] & 1 != 0;
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.get_metadata(database, from, to, with_value)?;
+
         let mut data = Vec::new();
         data.push(0); // no error
         data.extend_from_slice(&version.to_le_bytes());
         data.extend_from_slice(&(result.len() as u32).to_le_bytes());
-        for kv in result {
-            data.extend_from_slice(&kv.to_binary());
+        for (kv, metadata) in result {
+            data.extend_from_slice(&kv.version.to_le_bytes());
+            write_key(&mut data, kv.key, wide_keys);
+            encode_metadata(&metadata, &mut data);
+            if with_value {
+                data.extend_from_slice(&(kv.value.len() as u32).to_le_bytes());
+                data.extend_from_slice(&kv.value);
+            }
         }
         Ok(data)
     }
 
-    fn run_get_last_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
-        let (database, from, to) = parse_get_command_parameters(command)?;
+    fn run_find_by_attribute_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, mut idx) = get_database_name(command)?;
+        let kl = key_length(wide_keys);
+        if idx + kl * 2 + 1 > command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid find by attribute command length"));
+        }
+        let from = read_key(&command[idx..], wide_keys);
+        let to = read_key(&command[idx+kl..], wide_keys);
+        idx += kl * 2;
+        let name_length = command[idx] as usize;
+        idx += 1;
+        if idx + name_length > command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid find by attribute command length"));
+        }
+        let name = String::from_utf8(command[idx..idx+name_length].to_vec())
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        idx += name_length;
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.find_by_attribute(database, from, to, &name, &command[idx..])?;
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&(result.len() as u32).to_le_bytes());
+        for (key, file_version) in result {
+            data.extend_from_slice(&file_version.to_le_bytes());
+            write_key(&mut data, key, wide_keys);
+        }
+        Ok(data)
+    }
+
+    fn run_get_key_versions_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, from, to, options, with_modification) = parse_get_range_command_parameters(command, wide_keys)?;
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.get_key_versions(database, from, to, &options)?;
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&(result.len() as u32).to_le_bytes());
+        for (key, file_version, value_length, modification) in result {
+            data.extend_from_slice(&file_version.to_le_bytes());
+            write_key(&mut data, key, wide_keys);
+            data.extend_from_slice(&(value_length as u32).to_le_bytes());
+            if with_modification {
+                add_modification(&mut data, &modification);
+            }
+        }
+        Ok(data)
+    }
+
+    fn run_get_range_statistics_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, from, to) = parse_get_command_parameters(command, wide_keys)?;
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.get_range_statistics(database, from, to)?;
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&(result.count as u32).to_le_bytes());
+        data.extend_from_slice(&(result.total_size as u64).to_le_bytes());
+        write_key(&mut data, result.min_key, wide_keys);
+        write_key(&mut data, result.max_key, wide_keys);
+        data.extend_from_slice(&result.max_version.to_le_bytes());
+        Ok(data)
+    }
+
+    fn run_get_last_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, from, to) = parse_get_command_parameters(command, wide_keys)?;
         
         user.validate_access(&database, true)?;
         
-        let lock = self.data.read().unwrap();
-        let (version, result) = lock.get_last(database, from, to);
+        let (version, result) = self.data.get_last(database, from, to)?;
+        Ok(build_key_value_response(version, result, wide_keys))
+    }
+
+    fn run_get_first_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, from, to) = parse_get_command_parameters(command, wide_keys)?;
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.get_first(database, from, to)?;
+        Ok(build_key_value_response(version, result, wide_keys))
+    }
+
+    fn run_navigate_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool,
+                            navigation: Navigation) -> Result<Vec<u8>, Error> {
+        let (database, key) = parse_key_command_parameters(command, wide_keys)?;
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.navigate(database, key, navigation)?;
+        // keys that do not fit into u32 are not visible for narrow keys clients
+        let result = result.filter(|kv|wide_keys || kv.key <= u32::MAX as u64);
+        Ok(build_key_value_response(version, result, wide_keys))
+    }
+
+    fn run_get_keys_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, keys) = parse_get_keys_command_parameters(command, wide_keys)?;
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.get_keys(database, keys)?;
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&(result.len() as u32).to_le_bytes());
+        for kv in result {
+            if let Some(kv) = kv {
+                data.push(1);
+                data.extend_from_slice(&kv.to_binary(wide_keys));
+            } else {
+                data.push(0);
+            }
+        }
+        Ok(data)
+    }
+
+    fn run_get_changed_keys_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+        let versions = parse_key_versions(command, idx, wide_keys)?;
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.get_changed_keys(database, versions)?;
+        Ok(build_changed_response(version, result, wide_keys))
+    }
+
+    fn run_get_changed_range_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+        let kl = key_length(wide_keys);
+        if idx + kl * 2 > command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid get changed command length"));
+        }
+        let from = read_key(&command[idx..], wide_keys);
+        let to = read_key(&command[idx+kl..], wide_keys);
+        let versions = parse_key_versions(command, idx + kl * 2, wide_keys)?;
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.get_changed_range(database, from, to, versions.into_iter().collect())?;
+        Ok(build_changed_response(version, result, wide_keys))
+    }
+
+    fn run_get_value_part_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+        let kl = key_length(wide_keys);
+        if idx + kl + 8 != command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid get value part command length"));
+        }
+        let key = read_key(&command[idx..], wide_keys);
+        let offset = u32::from_le_bytes(command[idx+kl..idx+kl+4].try_into().unwrap()) as usize;
+        let length = u32::from_le_bytes(command[idx+kl+4..idx+kl+8].try_into().unwrap()) as usize;
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.get_value_part(database, key, offset, length)?;
 
         let mut data = Vec::new();
         data.push(0); // no error
         data.extend_from_slice(&version.to_le_bytes());
-        if let Some(kv) = result {
+        if let Some((file_version, full_length, value)) = result {
             data.push(1);
-            data.extend_from_slice(&kv.to_binary());
+            data.extend_from_slice(&file_version.to_le_bytes());
+            data.extend_from_slice(&(full_length as u32).to_le_bytes());
+            data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(&value);
         } else {
             data.push(0);
         }
         Ok(data)
     }
 
-    fn run_get_file_version_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
-        let (database, key) = parse_get_file_version_command_parameters(command)?;
+    fn run_get_file_version_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, key) = parse_key_command_parameters(command, wide_keys)?;
 
         user.validate_access(&database, true)?;
         
-        let lock = self.data.read().unwrap();
-        let (db_version, file_version) = lock.get_file_version(database, key);
+        let (db_version, file_version) = self.data.get_file_version(database, key)?;
 
         let mut data = Vec::new();
         data.push(0); // no error
@@ -100,45 +350,728 @@ impl UserCommandProcessor {
         Ok(data)
     }
     
-    fn run_set_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
+    fn run_set_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
         let (database, mut idx) = get_database_name(command)?;
 
         user.validate_access(&database, false)?;
 
         let expected_version = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap());
         idx += 4;
-        let data = KeyValue::from(&command[idx..])?;
-        let mut lock = self.data.write().unwrap();
-        lock.set(database, expected_version, data)?;
+        let data = KeyValue::from(&command[idx..], wide_keys)?;
+        self.data.set(database, expected_version, data, user.id)?;
+        Ok(vec![0]) // no error
+    }
+
+    fn run_write_value_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool,
+                               with_offset: bool) -> Result<Vec<u8>, Error> {
+        let (database, mut idx) = get_database_name(command)?;
+
+        user.validate_access(&database, false)?;
+
+        let header_length = 4 + key_length(wide_keys) + if with_offset {4} else {0};
+        if idx + header_length >= command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid write value command length"));
+        }
+        let expected_version = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap());
+        idx += 4;
+        let key = read_key(&command[idx..], wide_keys);
+        idx += key_length(wide_keys);
+        let offset = if with_offset {
+            let offset = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap()) as usize;
+            idx += 4;
+            Some(offset)
+        } else {None};
+        let (db_version, file_version) =
+            self.data.write_value(database, expected_version, key, offset, &command[idx..], user.id)?;
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.extend_from_slice(&db_version.to_le_bytes());
+        data.extend_from_slice(&file_version.to_le_bytes());
+        Ok(data)
+    }
+
+    fn run_patch_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+
+        user.validate_access(&database, false)?;
+
+        let kl = key_length(wide_keys);
+        if idx + kl + 8 > command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid patch command length"));
+        }
+        let expected_version = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap());
+        let key = read_key(&command[idx+4..], wide_keys);
+        let base_version = u32::from_le_bytes(command[idx+kl+4..idx+kl+8].try_into().unwrap());
+        let (db_version, file_version) =
+            self.data.patch(database, expected_version, key, base_version, &command[idx+kl+8..], user.id)?;
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.extend_from_slice(&db_version.to_le_bytes());
+        data.extend_from_slice(&file_version.to_le_bytes());
+        Ok(data)
+    }
+
+    fn run_increment_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+
+        user.validate_access(&database, false)?;
+
+        let kl = key_length(wide_keys);
+        if idx + kl + 8 != command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid increment command length"));
+        }
+        let key = read_key(&command[idx..], wide_keys);
+        let delta = i64::from_le_bytes(command[idx+kl..idx+kl+8].try_into().unwrap());
+        let (db_version, file_version, value) = self.data.increment(database, key, delta, user.id)?;
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.extend_from_slice(&db_version.to_le_bytes());
+        data.extend_from_slice(&file_version.to_le_bytes());
+        data.extend_from_slice(&value.to_le_bytes());
+        Ok(data)
+    }
+
+    // flags: bit 0 - pop last key, claim timeout in milliseconds: 0 - delete key, otherwise claim it
+    fn run_pop_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+
+        user.validate_access(&database, false)?;
+
+        let kl = key_length(wide_keys);
+        if idx + kl * 2 + 5 != command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid pop command length"));
+        }
+        let from = read_key(&command[idx..], wide_keys);
+        let to = read_key(&command[idx+kl..], wide_keys);
+        let idx = idx + kl * 2;
+        let last = command[idx] & 1 != 0;
+        let claim_timeout = match u32::from_le_bytes(command[idx+1..idx+5].try_into().unwrap()) {
+            0 => None,
+            t => Some(Duration::from_millis(t as u64))
+        };
+        let (version, result) = self.data.pop(database, from, to, last, claim_timeout)?;
+        Ok(build_key_value_response(version, result, wide_keys))
+    }
+
+    fn run_ack_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool, release: bool)
+        -> Result<Vec<u8>, Error> {
+        let (database, key) = parse_key_command_parameters(command, wide_keys)?;
+
+        user.validate_access(&database, false)?;
+
+        let version = self.data.ack(database, key, release)?;
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.extend_from_slice(&version.to_le_bytes());
+        Ok(data)
+    }
+
+    fn run_set_with_ttl_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, mut idx) = get_database_name(command)?;
+
+        user.validate_access(&database, false)?;
+
+        if idx + 8 > command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid set command length"));
+        }
+        let expected_version = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap());
+        idx += 4;
+        let ttl = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap());
+        if ttl == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid ttl"));
+        }
+        idx += 4;
+        let data = KeyValue::from(&command[idx..], wide_keys)?;
+        self.data.set_with_ttl(database, expected_version, data, user.id, Duration::from_millis(ttl as u64))?;
+        Ok(vec![0]) // no error
+    }
+
+    fn run_set_with_metadata_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, mut idx) = get_database_name(command)?;
+
+        user.validate_access(&database, false)?;
+
+        if idx + 4 > command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid set command length"));
+        }
+        let expected_version = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap());
+        idx += 4;
+        let data = KeyValue::from_with_metadata(&command[idx..], wide_keys)?;
+        self.data.set_with_metadata(database, expected_version, data, user.id)?;
+        Ok(vec![0]) // no error
+    }
+
+    fn run_append_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+
+        user.validate_access(&database, false)?;
+
+        let kl = key_length(wide_keys);
+        if idx + kl * 2 > command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid append command length"));
+        }
+        let from = read_key(&command[idx..], wide_keys);
+        let to = read_key(&command[idx+kl..], wide_keys);
+        let value = command[idx+kl*2..].to_vec();
+        let (key, version) = self.data.append(database, from, to, value, user.id)?;
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        write_key(&mut data, key, wide_keys);
+        data.extend_from_slice(&version.to_le_bytes());
+        Ok(data)
+    }
+
+    // returns databases visible to the user: name length: u8, name, access: u8 (0 - read, 1 - read write, 2 - admin),
+    // exists: u8, version: u32
+    fn run_list_databases_command(&self, user: &UserWithKey) -> Result<Vec<u8>, Error> {
+        let mut names: Vec<(&String, &bool)> = user.databases.iter().collect();
+        names.sort();
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        for (name, rw_access) in names {
+            data.push(name.len() as u8);
+            data.extend_from_slice(name.as_bytes());
+            data.push(if user.admin_databases.contains(name) {2} else if *rw_access {1} else {0});
+            let version = self.data.get_database_version(name);
+            data.push(if version.is_some() {1} else {0});
+            data.extend_from_slice(&version.unwrap_or(0).to_le_bytes());
+        }
+        Ok(data)
+    }
+
+    // flags: bit 0 - byte keys, hash divider: u64 (0 - server default)
+    fn run_create_database_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+        if idx + 9 != command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid create database command length"));
+        }
+        let settings = DatabaseSettings{
+            byte_keys: command[idx] & 1 != 0,
+            hash_divider: u64::from_le_bytes(command[idx+1..idx+9].try_into().unwrap())
+        };
+
+        user.validate_admin_access(&database)?;
+
+        self.data.create_database(database, settings)?;
+        Ok(vec![0]) // no error
+    }
+
+    fn run_drop_database_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+        if idx != command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid drop database command length"));
+        }
+
+        user.validate_admin_access(&database)?;
+
+        self.data.drop_database(database)?;
+        Ok(vec![0]) // no error
+    }
+
+    // source database read access and target database admin access are required
+    fn run_clone_database_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
+        let (source, target) = parse_two_database_names(command)?;
+
+        user.validate_access(&source, true)?;
+        user.validate_admin_access(&target)?;
+
+        self.data.clone_database(source, target)?;
+        Ok(vec![0]) // no error
+    }
+
+    fn run_rename_database_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
+        let (source, target) = parse_two_database_names(command)?;
+
+        user.validate_admin_access(&source)?;
+        user.validate_admin_access(&target)?;
+
+        self.data.rename_database(source, target)?;
+        Ok(vec![0]) // no error
+    }
+
+    fn run_swap_databases_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
+        let (database1, database2) = parse_two_database_names(command)?;
+
+        user.validate_admin_access(&database1)?;
+        user.validate_admin_access(&database2)?;
+
+        self.data.swap_databases(database1, database2)?;
+        Ok(vec![0]) // no error
+    }
+
+    // count: u8, [database, expected version: u32, data length: u32, data (set command format)]
+    // returns new database versions
+    fn run_transaction_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool)
+        -> Result<Vec<u8>, Error> {
+        if command.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid transaction command length"));
+        }
+        let mut count = command[0];
+        let mut idx = 1;
+        let mut groups = Vec::new();
+        while count > 0 {
+            let (database, length) = get_database_name(&command[idx..])?;
+            idx += length;
+            if idx + 8 > command.len() {
+                return Err(Error::new(ErrorKind::InvalidInput, "Invalid transaction command length"));
+            }
+            let expected_version = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap());
+            let data_length = u32::from_le_bytes(command[idx+4..idx+8].try_into().unwrap()) as usize;
+            idx += 8;
+            if idx + data_length > command.len() {
+                return Err(Error::new(ErrorKind::InvalidInput, "Invalid transaction command length"));
+            }
+            let data = KeyValue::from(&command[idx..idx+data_length], wide_keys)?;
+            idx += data_length;
+
+            user.validate_access(&database, false)?;
+
+            groups.push(TransactionGroup{database, expected_version, data});
+            count -= 1;
+        }
+        if idx != command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid transaction command length"));
+        }
+
+        let versions = self.data.transaction(groups, user.id)?;
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.push(versions.len() as u8);
+        for version in versions {
+            data.extend_from_slice(&version.to_le_bytes());
+        }
+        Ok(data)
+    }
+
+    // parameters: source database, target database, from, to, flags: bit 0 - move keys
+    fn run_copy_range_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool)
+        -> Result<Vec<u8>, Error> {
+        let (source, idx) = get_database_name(command)?;
+        let (target, length) = get_database_name(&command[idx..])?;
+        let idx = idx + length;
+        let kl = key_length(wide_keys);
+        if idx + kl * 2 + 1 != command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid copy range command length"));
+        }
+        let from = read_key(&command[idx..], wide_keys);
+        let to = read_key(&command[idx+kl..], wide_keys);
+        if from > to {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid key range"));
+        }
+        let move_keys = command[idx+kl*2] & 1 != 0;
+
+        user.validate_access(&source, !move_keys)?;
+        user.validate_access(&target, false)?;
+
+        let (source_version, target_version, count) =
+            self.data.copy_range(source, target, from, to, move_keys, user.id)?;
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.extend_from_slice(&source_version.to_le_bytes());
+        data.extend_from_slice(&target_version.to_le_bytes());
+        data.extend_from_slice(&(count as u32).to_le_bytes());
+        Ok(data)
+    }
+
+    fn run_get_statistics_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool)
+        -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+        if idx != command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid get statistics command length"));
+        }
+
+        user.validate_access(&database, true)?;
+
+        let result = self.data.get_statistics(database)?;
+        // min/max keys are not truncated for u32 keys clients
+        if !wide_keys && result.max_key > u32::MAX as u64 {
+            return Err(Error::new(ErrorKind::InvalidInput, "database keys do not fit u32, wide keys are required"));
+        }
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.extend_from_slice(&result.version.to_le_bytes());
+        data.extend_from_slice(&(result.count as u32).to_le_bytes());
+        data.extend_from_slice(&(result.total_size as u64).to_le_bytes());
+        write_key(&mut data, result.min_key, wide_keys);
+        write_key(&mut data, result.max_key, wide_keys);
+        data.extend_from_slice(&(result.max_value_size as u32).to_le_bytes());
+        data.extend_from_slice(&result.disk_size.to_le_bytes());
+        // milliseconds
+        data.extend_from_slice(&(result.load_time.as_millis() as u32).to_le_bytes());
+        Ok(data)
+    }
+
+    fn run_get_byte_keys_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
+        let (database, mut idx) = get_database_name(command)?;
+        if idx + 4 > command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid get keys command length"));
+        }
+        let mut count = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap());
+        idx += 4;
+        let mut keys = Vec::new();
+        while count > 0 {
+            let (key, key_length) = read_byte_key(&command[idx..])?;
+            keys.push(key);
+            idx += key_length;
+            count -= 1;
+        }
+        if idx != command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid get keys command length"));
+        }
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.get_byte_keys(database, keys)?;
+
+        let mut data = Vec::new();
+        data.push(0); // no error
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&(result.len() as u32).to_le_bytes());
+        for kv in result {
+            if let Some(kv) = kv {
+                data.push(1);
+                data.extend_from_slice(&kv.to_binary());
+            } else {
+                data.push(0);
+            }
+        }
+        Ok(data)
+    }
+
+    // prefix length: u8 (0 - all keys), prefix, optional scan parameters
+    fn run_get_byte_key_prefix_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+        if idx >= command.len() || idx + 1 + command[idx] as usize > command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid get prefix command length"));
+        }
+        let prefix_end = idx + 1 + command[idx] as usize;
+        let (options, _) = parse_scan_options(command, prefix_end)?;
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.get_byte_key_prefix(database, &command[idx+1..prefix_end], &options)?;
+        Ok(build_byte_key_get_response(version, result))
+    }
+
+    fn run_get_byte_key_range_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
+        let (database, mut idx) = get_database_name(command)?;
+        let (from, key_length) = read_byte_key(&command[idx..])?;
+        idx += key_length;
+        let (to, key_length) = read_byte_key(&command[idx..])?;
+        idx += key_length;
+        let (options, _) = parse_scan_options(command, idx)?;
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = self.data.get_byte_key_range(database, &from, &to, &options)?;
+        Ok(build_byte_key_get_response(version, result))
+    }
+
+    fn run_set_byte_keys_command(&self, user: &UserWithKey, command: &[u8]) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+
+        user.validate_access(&database, false)?;
+
+        if idx + 4 > command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid set command length"));
+        }
+        let expected_version = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap());
+        let data = ByteKeyValue::from(&command[idx+4..])?;
+        self.data.set_byte_keys(database, expected_version, data, user.id)?;
+        Ok(vec![0]) // no error
+    }
+
+    // composite keys: (u32, u32) keys are stored in integer keys database as first << 32 | second,
+    // command id bit 7 set - key components are sent as u64, but must not exceed u32::MAX.
+    // (u64, u64) keys (wide composite commands) are stored in byte keys database as 16 byte big endian values,
+    // key components are always sent as u64
+    // parameters: first, second from, second to, optional scan parameters (get last - no scan parameters)
+    fn run_composite_get_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool, last: bool,
+                                 wide_composite: bool) -> Result<Vec<u8>, Error> {
+        let (database, idx) = get_database_name(command)?;
+        let kl = key_length(wide_keys);
+        if idx + kl * 3 > command.len() || (last && idx + kl * 3 != command.len()) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid composite get command length"));
+        }
+        let first = read_key(&command[idx..], wide_keys);
+        let from = read_key(&command[idx+kl..], wide_keys);
+        let to = read_key(&command[idx+kl*2..], wide_keys);
+        let options = if last {
+            ScanOptions{descending: true, step: 1, limit: 1}
+        } else {
+            parse_scan_options(command, idx + kl * 3)?.0
+        };
+
+        user.validate_access(&database, true)?;
+
+        let (version, result) = if wide_composite {
+            let (version, result) = self.data.get_byte_key_range(database, &wide_composite_key(first, from),
+                                                            &wide_composite_key(first, to), &options)?;
+            (version, result.into_iter()
+                .filter_map(|kv|split_wide_composite_key(&kv.key)
+                    .map(|(first, second)|(first, second, kv.version, kv.value)))
+                .collect())
+        } else {
+            validate_composite_key_components(&[first, from, to])?;
+            let (version, result) = self.data.get(database, composite_key(first, from), composite_key(first, to),
+                                             &options)?;
+            (version, result.into_iter()
+                .map(|(kv, _)|{
+                    let (first, second) = split_composite_key(kv.key);
+                    (first, second, kv.version, kv.value)
+                })
+                .collect())
+        };
+        Ok(build_composite_response(version, result, wide_keys))
+    }
+
+    // parameters: expected version, count, [first, second, value length, value]
+    fn run_composite_set_command(&self, user: &UserWithKey, command: &[u8], wide_keys: bool, wide_composite: bool)
+        -> Result<Vec<u8>, Error> {
+        let (database, mut idx) = get_database_name(command)?;
+
+        user.validate_access(&database, false)?;
+
+        if idx + 8 > command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid set command length"));
+        }
+        let expected_version = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap());
+        let mut count = u32::from_le_bytes(command[idx+4..idx+8].try_into().unwrap());
+        idx += 8;
+        let kl = key_length(wide_keys);
+        let mut data = Vec::new();
+        while count > 0 {
+            if idx + kl * 2 + 4 > command.len() {
+                return Err(Error::new(ErrorKind::InvalidInput, "Invalid set command length"));
+            }
+            let first = read_key(&command[idx..], wide_keys);
+            let second = read_key(&command[idx+kl..], wide_keys);
+            idx += kl * 2;
+            let value_length = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap()) as usize;
+            idx += 4;
+            if idx + value_length > command.len() {
+                return Err(Error::new(ErrorKind::InvalidInput, "Invalid set command length"));
+            }
+            data.push((first, second, command[idx..idx+value_length].to_vec()));
+            idx += value_length;
+            count -= 1;
+        }
+        if idx != command.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid set command length"));
+        }
+        if wide_composite {
+            let data = data.into_iter()
+                .map(|(first, second, value)|ByteKeyValue{key: wide_composite_key(first, second), version: 0, value})
+                .collect();
+            self.data.set_byte_keys(database, expected_version, data, user.id)?;
+        } else {
+            for (first, second, _) in &data {
+                validate_composite_key_components(&[*first, *second])?;
+            }
+            let data = data.into_iter()
+                .map(|(first, second, value)|KeyValue{key: composite_key(first, second), version: 0, value})
+                .collect();
+            self.data.set(database, expected_version, data, user.id)?;
+        }
         Ok(vec![0]) // no error
     }
 }
 
-fn parse_get_command_parameters(command: &[u8]) -> Result<(String, usize, usize), Error> {
+// (u32, u32) composite key components sent as u64
+fn validate_composite_key_components(components: &[u64]) -> Result<(), Error> {
+    if components.iter().any(|c|*c > u32::MAX as u64) {
+        return Err(Error::new(ErrorKind::InvalidInput, "composite key component is too large"));
+    }
+    Ok(())
+}
+
+fn build_composite_response(version: u32, result: Vec<(u64, u64, u32, Vec<u8>)>, wide_keys: bool) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.push(0); // no error
+    data.extend_from_slice(&version.to_le_bytes());
+    data.extend_from_slice(&(result.len() as u32).to_le_bytes());
+    for (first, second, file_version, value) in result {
+        data.extend_from_slice(&file_version.to_le_bytes());
+        write_key(&mut data, first, wide_keys);
+        write_key(&mut data, second, wide_keys);
+        data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        data.extend_from_slice(&value);
+    }
+    data
+}
+
+fn build_byte_key_get_response(version: u32, result: Vec<ByteKeyValue>) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.push(0); // no error
+    data.extend_from_slice(&version.to_le_bytes());
+    data.extend_from_slice(&(result.len() as u32).to_le_bytes());
+    for kv in result {
+        data.extend_from_slice(&kv.to_binary());
+    }
+    data
+}
+
+fn build_get_response(version: u32, result: Vec<(KeyValue, Modification)>, with_modification: bool, wide_keys: bool)
+    -> Vec<u8> {
+    let mut data = Vec::new();
+    data.push(0); // no error
+    data.extend_from_slice(&version.to_le_bytes());
+    data.extend_from_slice(&(result.len() as u32).to_le_bytes());
+    for (kv, modification) in result {
+        data.extend_from_slice(&kv.to_binary(wide_keys));
+        if with_modification {
+            add_modification(&mut data, &modification);
+        }
+    }
+    data
+}
+
+fn add_modification(data: &mut Vec<u8>, modification: &Modification) {
+    data.extend_from_slice(&modification.time.to_le_bytes());
+    data.extend_from_slice(&modification.user_id.to_le_bytes());
+}
+
+fn build_key_value_response(version: u32, result: Option<KeyValue>, wide_keys: bool) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.push(0); // no error
+    data.extend_from_slice(&version.to_le_bytes());
+    if let Some(kv) = result {
+        data.push(1);
+        data.extend_from_slice(&kv.to_binary(wide_keys));
+    } else {
+        data.push(0);
+    }
+    data
+}
+
+fn build_changed_response(version: u32, result: Vec<(u64, Option<KeyValue>)>, wide_keys: bool) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.push(0); // no error
+    data.extend_from_slice(&version.to_le_bytes());
+    data.extend_from_slice(&(result.len() as u32).to_le_bytes());
+    for (key, kv) in result {
+        if let Some(kv) = kv {
+            data.push(1);
+            data.extend_from_slice(&kv.to_binary(wide_keys));
+        } else {
+            // key was deleted
+            data.push(0);
+            write_key(&mut data, key, wide_keys);
+        }
+    }
+    data
+}
+
+fn parse_get_command_parameters(command: &[u8], wide_keys: bool) -> Result<(String, u64, u64), Error> {
     let (database, idx) = get_database_name(command)?;
-    if idx + 8 != command.len() {
+    let kl = key_length(wide_keys);
+    if idx + kl * 2 != command.len() {
         return Err(Error::new(ErrorKind::InvalidInput, "Invalid get command length"));
     }
-    let mut buffer32 = [0u8; 4];
-    buffer32.clone_from_slice(&command[idx..idx+4]);
-    let from = u32::from_le_bytes(buffer32) as usize;
-    buffer32.clone_from_slice(&command[idx+4..idx+8]);
-    let to = u32::from_le_bytes(buffer32) as usize;
+    let from = read_key(&command[idx..], wide_keys);
+    let to = read_key(&command[idx+kl..], wide_keys);
     Ok((database, from, to))
 }
 
-fn parse_get_file_version_command_parameters(command: &[u8]) -> Result<(String, usize), Error> {
+fn parse_get_range_command_parameters(command: &[u8], wide_keys: bool)
+    -> Result<(String, u64, u64, ScanOptions, bool), Error> {
+    let (database, idx) = get_database_name(command)?;
+    let kl = key_length(wide_keys);
+    if idx + kl * 2 > command.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid get command length"));
+    }
+    let from = read_key(&command[idx..], wide_keys);
+    let to = read_key(&command[idx+kl..], wide_keys);
+    let (options, with_modification) = parse_scan_options(command, idx + kl * 2)?;
+    Ok((database, from, to, options, with_modification))
+}
+
+// optional scan parameters: flags (bit 0 - descending order, bit 1 - return modification time and user id),
+// step, limit (0 - no limit)
+fn parse_scan_options(command: &[u8], idx: usize) -> Result<(ScanOptions, bool), Error> {
+    if idx == command.len() {
+        return Ok((ScanOptions::default(), false));
+    }
+    if idx + 9 != command.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid get command length"));
+    }
+    let descending = command[idx] & 1 != 0;
+    let with_modification = command[idx] & 2 != 0;
+    let step = u32::from_le_bytes(command[idx+1..idx+5].try_into().unwrap()) as usize;
+    if step == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid get command step"));
+    }
+    let limit = match u32::from_le_bytes(command[idx+5..idx+9].try_into().unwrap()) {
+        0 => usize::MAX,
+        l => l as usize
+    };
+    Ok((ScanOptions{descending, step, limit}, with_modification))
+}
+
+fn parse_key_command_parameters(command: &[u8], wide_keys: bool) -> Result<(String, u64), Error> {
     let (database, idx) = get_database_name(command)?;
-    if idx + 4 != command.len() {
+    if idx + key_length(wide_keys) != command.len() {
         return Err(Error::new(ErrorKind::InvalidInput, "Invalid get command length"));
     }
-    let mut buffer32 = [0u8; 4];
-    buffer32.clone_from_slice(&command[idx..idx+4]);
-    let key = u32::from_le_bytes(buffer32) as usize;
+    let key = read_key(&command[idx..], wide_keys);
     Ok((database, key))
 }
 
+fn parse_get_keys_command_parameters(command: &[u8], wide_keys: bool) -> Result<(String, Vec<u64>), Error> {
+    let (database, idx) = get_database_name(command)?;
+    if idx + 4 > command.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid get keys command length"));
+    }
+    let count = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap()) as usize;
+    let kl = key_length(wide_keys);
+    if idx + 4 + count * kl != command.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid get keys command length"));
+    }
+    let keys = command[idx+4..].chunks(kl)
+        .map(|k|read_key(k, wide_keys))
+        .collect();
+    Ok((database, keys))
+}
+
+fn parse_key_versions(command: &[u8], idx: usize, wide_keys: bool) -> Result<Vec<(u64, u32)>, Error> {
+    if idx + 4 > command.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid get changed command length"));
+    }
+    let count = u32::from_le_bytes(command[idx..idx+4].try_into().unwrap()) as usize;
+    let kl = key_length(wide_keys);
+    if idx + 4 + count * (kl + 4) != command.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid get changed command length"));
+    }
+    let versions = command[idx+4..].chunks(kl + 4)
+        .map(|kv|(read_key(kv, wide_keys), u32::from_le_bytes(kv[kl..kl+4].try_into().unwrap())))
+        .collect();
+    Ok(versions)
+}
+
+fn parse_two_database_names(command: &[u8]) -> Result<(String, String), Error> {
+    let (database1, idx) = get_database_name(command)?;
+    let (database2, length) = get_database_name(&command[idx..])?;
+    if idx + length != command.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid command length"));
+    }
+    Ok((database1, database2))
+}
+
+// name length: u8, name
 fn get_database_name(command: &[u8]) -> Result<(String, usize), Error> {
+    if command.is_empty() || command.len() < command[0] as usize + 1 {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid database name length"));
+    }
     let length = command[0] as usize;
     let name = String::from_utf8(command[1..length+1].to_vec())
         .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
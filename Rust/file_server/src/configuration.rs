@@ -24,11 +24,21 @@ pub struct Configuration {
     #[serde(rename = "BaseFolder")]
     pub base_folder: String,
     #[serde(rename = "HashDivider")]
-    pub hash_divider: usize,
+    pub hash_divider: u64,
+    // databases with byte string keys
+    #[serde(rename = "ByteKeyDatabases", default)]
+    pub byte_key_databases: HashSet<String>,
+    // false - databases must be created with create database command
+    #[serde(rename = "ImplicitDatabaseCreation", default = "default_implicit_database_creation")]
+    pub implicit_database_creation: bool,
     #[serde(rename = "Users")]
     pub users: Vec<User>
 }
 
+fn default_implicit_database_creation() -> bool {
+    true
+}
+
 pub fn load_configuration(ini_file_name: &String) -> Result<Configuration, Error> {
     let file = File::open(ini_file_name)?;
     let reader = BufReader::new(file);
@@ -74,6 +84,9 @@ mod tests {
         assert_eq!(config.port_number, 59999, "incorrect PortNumber value");
         assert_eq!(config.base_folder, "/tmp", "incorrect base folder");
         assert_eq!(config.hash_divider, 10000, "incorrect hash divider");
+        assert_eq!(config.byte_key_databases.len(), 1, "incorrect byte key databases");
+        assert!(config.byte_key_databases.contains("names"), "incorrect byte key databases");
+        assert!(!config.implicit_database_creation, "incorrect implicit database creation");
         assert_eq!(config.users.len(), 1, "incorrect number of users");
         let user = &config.users[0];
         assert_eq!(user.id, 11223344);
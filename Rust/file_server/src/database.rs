@@ -1,35 +1,132 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::io::{Error, ErrorKind, Write};
-use std::path::PathBuf;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::ops::RangeBounds;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// set in stored file version when file has extended header
+const EXTENDED_HEADER_FLAG: u32 = 0x80000000;
+// extended header attribute tags
+const EXPIRES_AT_TAG: u8 = 1;
+const MODIFIED_AT_TAG: u8 = 2;
+const USER_ID_TAG: u8 = 3;
+const METADATA_TAG: u8 = 4;
+// limits memory used by patch reconstruction, every copy operation can repeat the whole base value
+const MAX_PATCHED_VALUE_SIZE: usize = 64 * 1024 * 1024;
+// number of key folders is limited, so 64 bit and composite keys do not create a folder per key
+const MAX_FOLDERS: u64 = 65536;
+
+pub type Metadata = BTreeMap<String, Vec<u8>>;
 
 #[derive(Clone)]
 pub struct KeyValue {
-    pub key: usize,
+    pub key: u64,
     pub version: u32,
     pub value: Vec<u8>,
 }
 
 pub struct KeyValueRef<'a> {
-    pub key: usize,
+    pub key: u64,
     pub version: u32,
     pub value: &'a Vec<u8>,
+    pub modified: Modification,
+    pub metadata: &'a Metadata,
+    // unix time in milliseconds, None - key never expires
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Modification {
+    // unix time in milliseconds
+    pub time: u64,
+    // 0 - unknown
+    pub user_id: u32,
+}
+
+pub struct ScanOptions {
+    pub descending: bool,
+    pub step: usize,
+    pub limit: usize,
+}
+
+pub enum Navigation {
+    Floor,
+    Ceiling,
+    Next,
+    Previous,
+}
+
+#[derive(Default)]
+pub struct RangeStatistics {
+    pub count: usize,
+    pub total_size: usize,
+    pub min_key: u64,
+    pub max_key: u64,
+    pub max_version: u32,
+}
+
+pub struct File {
+    pub version: u32,
+    pub data: Vec<u8>,
+    // unix time in milliseconds
+    pub expires_at: Option<u64>,
+    pub modified: Modification,
+    pub metadata: Metadata
+}
+
+#[derive(Default)]
+pub struct DatabaseStatistics {
+    pub count: usize,
+    pub total_size: usize,
+    pub min_key: u64,
+    pub max_key: u64,
+    pub version: u32,
+    pub max_value_size: usize,
+    pub disk_size: u64,
+    pub load_time: Duration,
+}
+
+// counters maintained on every change, so statistics do not need a full scan
+#[derive(Default)]
+struct SizeCounters {
+    total_size: usize,
+    disk_size: u64,
+    // value size -> number of values with this size
+    value_sizes: BTreeMap<usize, usize>,
+}
+
+// expiration time of changed key
+#[derive(Clone, Copy)]
+enum Expiration {
+    Never,
+    After(Duration),
+    // unix time in milliseconds
+    At(u64),
+    // partial value updates keep expiration time of existing key
+    Keep
 }
 
-struct File {
+// database version and previous files of changed keys, used to revert a committed change
+pub struct Changes {
     version: u32,
-    data: Vec<u8>
+    files: Vec<(u64, Option<File>)>
 }
 
 pub struct Database {
     base_folder: PathBuf,
-    hash_divider: usize,
+    hash_divider: u64,
     version: u32,
-    data: BTreeMap<usize, File>
+    data: BTreeMap<u64, File>,
+    // claimed key -> claim deadline, claimed file version
+    claims: HashMap<u64, (Instant, u32)>,
+    counters: SizeCounters,
+    load_time: Duration
 }
 
 impl KeyValue {
-    pub fn from(data: &[u8]) -> Result<Vec<KeyValue>, Error> {
+    pub fn from(data: &[u8], wide_keys: bool) -> Result<Vec<KeyValue>, Error> {
         let l = data.len();
         if l < 4 {
             return Err(Error::new(ErrorKind::InvalidInput, "data is too short"));
@@ -37,14 +134,18 @@ impl KeyValue {
         let mut length = u32::from_le_bytes(data[0..4].try_into().unwrap());
         let mut idx = 4;
         let mut result = Vec::new();
+        let kl = key_length(wide_keys);
         while length > 0 {
-            if l < idx + 8 {
+            if l < idx + kl + 4 {
                 return Err(Error::new(ErrorKind::InvalidInput, "data is too short"));
             }
-            let key = u32::from_le_bytes(data[idx..idx + 4].try_into().unwrap()) as usize;
-            idx += 4;
+            let key = read_key(&data[idx..], wide_keys);
+            idx += kl;
             let value_length = u32::from_le_bytes(data[idx..idx + 4].try_into().unwrap()) as usize;
             idx += 4;
+            if l < idx + value_length {
+                return Err(Error::new(ErrorKind::InvalidInput, "data is too short"));
+            }
             let value = Vec::from(&data[idx..idx + value_length]);
             idx += value_length;
             length -= 1;
@@ -56,106 +157,806 @@ impl KeyValue {
         Ok(result)
     }
     
-    pub fn to_binary(&self) -> Vec<u8> {
+    // same as from, but each key is followed by metadata, see decode_metadata
+    pub fn from_with_metadata(data: &[u8], wide_keys: bool) -> Result<Vec<(KeyValue, Metadata)>, Error> {
+        let l = data.len();
+        if l < 4 {
+            return Err(Error::new(ErrorKind::InvalidInput, "data is too short"));
+        }
+        let mut length = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let mut idx = 4;
+        let mut result = Vec::new();
+        let kl = key_length(wide_keys);
+        while length > 0 {
+            if l < idx + kl {
+                return Err(Error::new(ErrorKind::InvalidInput, "data is too short"));
+            }
+            let key = read_key(&data[idx..], wide_keys);
+            idx += kl;
+            let (metadata, metadata_length) = decode_metadata(&data[idx..])?;
+            idx += metadata_length;
+            if l < idx + 4 {
+                return Err(Error::new(ErrorKind::InvalidInput, "data is too short"));
+            }
+            let value_length = u32::from_le_bytes(data[idx..idx + 4].try_into().unwrap()) as usize;
+            idx += 4;
+            if l < idx + value_length {
+                return Err(Error::new(ErrorKind::InvalidInput, "data is too short"));
+            }
+            let value = Vec::from(&data[idx..idx + value_length]);
+            idx += value_length;
+            length -= 1;
+            result.push((KeyValue{key, version: 0, value}, metadata));
+        }
+        if idx != l {
+            return Err(Error::new(ErrorKind::InvalidInput, "incorrect data size"));
+        }
+        Ok(result)
+    }
+
+    pub fn to_binary(&self, wide_keys: bool) -> Vec<u8> {
         let mut result = Vec::new();
         result.extend_from_slice(&self.version.to_le_bytes());
-        result.extend_from_slice(&(self.key as u32).to_le_bytes());
+        write_key(&mut result, self.key, wide_keys);
         result.extend_from_slice(&(self.value.len() as u32).to_le_bytes());
         result.extend_from_slice(&self.value);
         result
     }
 }
 
+impl File {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map(|t|t <= now).unwrap_or(false)
+    }
+
+    fn to_ref(&self, key: u64) -> KeyValueRef<'_> {
+        KeyValueRef{key, version: self.version, value: &self.data, modified: self.modified, metadata: &self.metadata,
+                    expires_at: self.expires_at}
+    }
+
+    // version: u32, [header length: u32, header], data
+    // header is a list of attributes: tag: u8, length: u32, value
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut header = Vec::new();
+        if let Some(expires_at) = self.expires_at {
+            header.push(EXPIRES_AT_TAG);
+            header.extend_from_slice(&8u32.to_le_bytes());
+            header.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        if self.modified.time != 0 {
+            header.push(MODIFIED_AT_TAG);
+            header.extend_from_slice(&8u32.to_le_bytes());
+            header.extend_from_slice(&self.modified.time.to_le_bytes());
+        }
+        if self.modified.user_id != 0 {
+            header.push(USER_ID_TAG);
+            header.extend_from_slice(&4u32.to_le_bytes());
+            header.extend_from_slice(&self.modified.user_id.to_le_bytes());
+        }
+        if !self.metadata.is_empty() {
+            let mut metadata = Vec::new();
+            encode_metadata(&self.metadata, &mut metadata);
+            header.push(METADATA_TAG);
+            header.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+            header.extend_from_slice(&metadata);
+        }
+        let mut result = Vec::new();
+        if header.is_empty() {
+            result.extend_from_slice(&self.version.to_le_bytes());
+        } else {
+            result.extend_from_slice(&(self.version | EXTENDED_HEADER_FLAG).to_le_bytes());
+            result.extend_from_slice(&(header.len() as u32).to_le_bytes());
+            result.extend_from_slice(&header);
+        }
+        result.extend_from_slice(&self.data);
+        result
+    }
+
+    // same as to_binary().len(), but without building file contents
+    fn binary_size(&self) -> u64 {
+        let mut header = 0;
+        if self.expires_at.is_some() {
+            header += 13;
+        }
+        if self.modified.time != 0 {
+            header += 13;
+        }
+        if self.modified.user_id != 0 {
+            header += 9;
+        }
+        if !self.metadata.is_empty() {
+            header += 6 + self.metadata.iter().map(|(k, v)|5 + k.len() + v.len()).sum::<usize>();
+        }
+        let header_size = if header == 0 {0} else {4 + header};
+        (4 + header_size + self.data.len()) as u64
+    }
+
+    pub fn from_binary(data: &[u8]) -> Result<File, Error> {
+        let l = data.len();
+        if l < 4 {
+            return Err(Error::new(ErrorKind::InvalidData, "file is too short"));
+        }
+        let version = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let mut file = File{version: version & !EXTENDED_HEADER_FLAG, data: Vec::new(), expires_at: None,
+                            modified: Modification::default(), metadata: Metadata::new()};
+        let mut idx = 4;
+        if version & EXTENDED_HEADER_FLAG != 0 {
+            if l < 8 {
+                return Err(Error::new(ErrorKind::InvalidData, "file is too short"));
+            }
+            let header_end = 8 + u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+            if l < header_end {
+                return Err(Error::new(ErrorKind::InvalidData, "file is too short"));
+            }
+            idx = 8;
+            while idx < header_end {
+                if header_end < idx + 5 {
+                    return Err(Error::new(ErrorKind::InvalidData, "incorrect file header"));
+                }
+                let tag = data[idx];
+                let length = u32::from_le_bytes(data[idx+1..idx+5].try_into().unwrap()) as usize;
+                idx += 5;
+                if header_end < idx + length {
+                    return Err(Error::new(ErrorKind::InvalidData, "incorrect file header"));
+                }
+                let value = &data[idx..idx+length];
+                let header_error = |_|Error::new(ErrorKind::InvalidData, "incorrect file header");
+                match tag {
+                    EXPIRES_AT_TAG => file.expires_at = Some(u64::from_le_bytes(value.try_into().map_err(header_error)?)),
+                    MODIFIED_AT_TAG => file.modified.time = u64::from_le_bytes(value.try_into().map_err(header_error)?),
+                    USER_ID_TAG => file.modified.user_id = u32::from_le_bytes(value.try_into().map_err(header_error)?),
+                    METADATA_TAG => file.metadata = decode_metadata(value)?.0,
+                    _ => {}
+                }
+                idx += length;
+            }
+        }
+        file.data = data[idx..].to_vec();
+        Ok(file)
+    }
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions{descending: false, step: 1, limit: usize::MAX}
+    }
+}
+
+impl SizeCounters {
+    fn add(&mut self, file: &File) {
+        self.total_size += file.data.len();
+        self.disk_size += file.binary_size();
+        *self.value_sizes.entry(file.data.len()).or_insert(0) += 1;
+    }
+
+    fn remove(&mut self, file: &File) {
+        self.total_size -= file.data.len();
+        self.disk_size -= file.binary_size();
+        if let Some(count) = self.value_sizes.get_mut(&file.data.len()) {
+            *count -= 1;
+            if *count == 0 {
+                self.value_sizes.remove(&file.data.len());
+            }
+        }
+    }
+}
+
 impl Database {
-    pub fn new(base_folder: PathBuf, hash_divider: usize) -> Result<Database, Error> {
-        let data = load_data(&base_folder)?;
-        Ok(Database{base_folder, hash_divider, data, version: 1})
+    pub fn new(base_folder: PathBuf, hash_divider: u64) -> Result<Database, Error> {
+        let start = Instant::now();
+        let data = load_data(&base_folder, hash_divider)?;
+        let mut counters = SizeCounters::default();
+        for file in data.values() {
+            counters.add(file);
+        }
+        Ok(Database{base_folder, hash_divider, data, version: 1, claims: HashMap::new(), counters,
+                    load_time: start.elapsed()})
+    }
+
+    // expired keys are counted until they are removed by remove_expired
+    pub fn get_statistics(&self) -> DatabaseStatistics {
+        DatabaseStatistics{
+            count: self.data.len(),
+            total_size: self.counters.total_size,
+            min_key: self.data.first_key_value().map(|(k, _)|*k).unwrap_or(0),
+            max_key: self.data.last_key_value().map(|(k, _)|*k).unwrap_or(0),
+            version: self.version,
+            max_value_size: self.counters.value_sizes.last_key_value().map(|(s, _)|*s).unwrap_or(0),
+            disk_size: self.counters.disk_size,
+            load_time: self.load_time,
+        }
     }
 
     pub fn get_version(&self) -> u32 {
         self.version
     }
+
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    pub fn get_hash_divider(&self) -> u64 {
+        self.hash_divider
+    }
+
+    // database folder was renamed
+    pub fn set_base_folder(&mut self, base_folder: PathBuf) {
+        self.base_folder = base_folder;
+    }
     
-    pub fn get(&self, key1: usize, key2: usize) -> Vec<KeyValueRef> {
-        self.data.range(key1..=key2)
-            .map(|(k, value)|KeyValueRef{key: *k, version: value.version, value: &value.data})
+    pub fn get(&self, key1: u64, key2: u64, options: &ScanOptions) -> Vec<KeyValueRef> {
+        let range = self.range(key1..=key2);
+        let items: Vec<(&u64, &File)> = if options.descending {
+            range.rev().step_by(options.step).take(options.limit).collect()
+        } else {
+            range.step_by(options.step).take(options.limit).collect()
+        };
+        items.into_iter()
+            .map(|(k, value)|value.to_ref(*k))
             .collect()
     }
 
-    pub fn get_last(&self, key1: usize, key2: usize) -> Option<KeyValueRef> {
-        self.data.range(key1..=key2).last()
-            .map(|(k, value)|KeyValueRef{key: *k, version: value.version, value: &value.data})
+    pub fn get_last(&self, key1: u64, key2: u64) -> Option<KeyValueRef> {
+        self.range(key1..=key2).next_back()
+            .map(|(k, value)|value.to_ref(*k))
     }
     
-    pub fn set(&mut self, expected_version: u32, data: Vec<KeyValue>) -> Result<(), Error> {
+    pub fn get_key(&self, key: u64) -> Option<KeyValueRef<'_>> {
+        self.get_file(key)
+            .map(|value|value.to_ref(key))
+    }
+
+    pub fn get_first(&self, key1: u64, key2: u64) -> Option<KeyValueRef<'_>> {
+        self.range(key1..=key2).next()
+            .map(|(k, value)|value.to_ref(*k))
+    }
+
+    pub fn navigate(&self, key: u64, navigation: Navigation) -> Option<KeyValueRef<'_>> {
+        let item = match navigation {
+            Navigation::Floor => self.range(..=key).next_back(),
+            Navigation::Ceiling => self.range(key..).next(),
+            Navigation::Next => self.range((Excluded(key), Unbounded)).next(),
+            Navigation::Previous => self.range(..key).next_back(),
+        };
+        item.map(|(k, value)|value.to_ref(*k))
+    }
+
+    pub fn get_range_statistics(&self, key1: u64, key2: u64) -> RangeStatistics {
+        let mut result = RangeStatistics::default();
+        for (k, value) in self.range(key1..=key2) {
+            if result.count == 0 {
+                result.min_key = *k;
+            }
+            result.count += 1;
+            result.total_size += value.data.len();
+            result.max_key = *k;
+            result.max_version = result.max_version.max(value.version);
+        }
+        result
+    }
+
+    pub fn find_by_attribute(&self, key1: u64, key2: u64, name: &str, value: &[u8]) -> Vec<KeyValueRef<'_>> {
+        self.range(key1..=key2)
+            .filter(|(_, f)|f.metadata.get(name).map(|v|v.as_slice() == value).unwrap_or(false))
+            .map(|(k, f)|f.to_ref(*k))
+            .collect()
+    }
+
+    pub fn get_modified_since(&self, key1: u64, key2: u64, since: u64) -> Vec<KeyValueRef<'_>> {
+        self.range(key1..=key2)
+            .filter(|(_, value)|value.modified.time > since)
+            .map(|(k, value)|value.to_ref(*k))
+            .collect()
+    }
+
+    // known version 0 means that client has no such key, deleted keys are returned with None value
+    pub fn get_changed_keys(&self, versions: Vec<(u64, u32)>) -> Vec<(u64, Option<KeyValueRef<'_>>)> {
+        versions.into_iter()
+            .filter_map(|(key, version)|match self.get_file(key) {
+                Some(value) => if value.version != version {
+                    Some((key, Some(value.to_ref(key))))
+                } else {None},
+                None => if version != 0 {Some((key, None))} else {None}
+            })
+            .collect()
+    }
+
+    pub fn get_changed_range(&self, key1: u64, key2: u64, versions: &HashMap<u64, u32>)
+        -> Vec<(u64, Option<KeyValueRef<'_>>)> {
+        let mut result: Vec<(u64, Option<KeyValueRef>)> = self.range(key1..=key2)
+            .filter(|(k, value)|versions.get(k) != Some(&value.version))
+            .map(|(k, value)|(*k, Some(value.to_ref(*k))))
+            .collect();
+        result.extend(versions.iter()
+            .filter(|(k, version)|**version != 0 && **k >= key1 && **k <= key2 && self.get_file(**k).is_none())
+            .map(|(k, _)|(*k, None)));
+        result.sort_by_key(|(k, _)|*k);
+        result
+    }
+
+    pub fn set(&mut self, expected_version: u32, data: Vec<KeyValue>, user_id: u32) -> Result<(), Error> {
+        self.set_with_ttl(expected_version, data, user_id, None)
+    }
+
+    // keys stored without ttl never expire
+    pub fn set_with_ttl(&mut self, expected_version: u32, data: Vec<KeyValue>, user_id: u32, ttl: Option<Duration>)
+        -> Result<(), Error> {
+        let expiration = match ttl {
+            Some(ttl) => Expiration::After(ttl),
+            None => Expiration::Never
+        };
+        self.commit(expected_version, data.into_iter().map(|kv|(kv, None, expiration)).collect(), user_id)?;
+        Ok(())
+    }
+
+    // same as set, returned changes can be reverted while database is locked
+    pub fn set_revertible(&mut self, expected_version: u32, data: Vec<KeyValue>, user_id: u32)
+        -> Result<Changes, Error> {
+        self.commit(expected_version, data.into_iter().map(|kv|(kv, None, Expiration::Never)).collect(), user_id)
+    }
+
+    // restores keys and version, key claims are not restored
+    pub fn revert(&mut self, changes: Changes) -> Result<(), Error> {
+        for (key, file) in changes.files.into_iter().rev() {
+            self.replace(key, file)?;
+        }
+        self.version = changes.version;
+        Ok(())
+    }
+
+    // replaces keys metadata, set and set_with_ttl keep existing metadata
+    pub fn set_with_metadata(&mut self, expected_version: u32, data: Vec<(KeyValue, Metadata)>, user_id: u32)
+        -> Result<(), Error> {
+        self.commit(expected_version, data.into_iter().map(|(kv, m)|(kv, Some(m), Expiration::Never)).collect(),
+                    user_id)?;
+        Ok(())
+    }
+
+    // stores keys copied from another database with their metadata and expiration time,
+    // returned changes can be reverted while database is locked
+    pub fn set_copies(&mut self, expected_version: u32, data: Vec<(KeyValue, Metadata, Option<u64>)>, user_id: u32)
+        -> Result<Changes, Error> {
+        let data = data.into_iter()
+            .map(|(kv, m, expires_at)|(kv, Some(m), expires_at.map(Expiration::At).unwrap_or(Expiration::Never)))
+            .collect();
+        self.commit(expected_version, data, user_id)
+    }
+
+    // changes value of existing key, keeps its metadata and expiration time
+    fn update(&mut self, expected_version: u32, key: u64, value: Vec<u8>, user_id: u32) -> Result<(), Error> {
+        self.commit(expected_version, vec![(KeyValue{key, version: 0, value}, None, Expiration::Keep)], user_id)?;
+        Ok(())
+    }
+
+    // keys changed before a file write error are reverted
+    fn commit(&mut self, expected_version: u32, data: Vec<(KeyValue, Option<Metadata>, Expiration)>, user_id: u32)
+        -> Result<Changes, Error> {
         if expected_version != self.version {
             return Err(Error::new(ErrorKind::InvalidData, "version mismatch"));
         }
+        let mut changes = Changes{version: self.version, files: Vec::new()};
         self.version += 1;
-        for kv in data {
-            if kv.value.len() != 0 {
-                let version = self.data.get(&kv.key).map(|f| f.version).unwrap_or(0) + 1;
-                self.save(kv.key, version, &kv.value)?;
-                self.data.insert(kv.key, File { version, data: kv.value });
+        let now = unix_time_ms();
+        let modified = Modification{time: now, user_id};
+        for (kv, metadata, expiration) in data {
+            let file = if kv.value.len() != 0 {
+                let old = self.data.get(&kv.key);
+                let version = old.map(|f| f.version).unwrap_or(0) + 1;
+                let metadata = metadata
+                    .unwrap_or_else(||old.map(|f|f.metadata.clone()).unwrap_or_default());
+                let expires_at = match expiration {
+                    Expiration::Never => None,
+                    Expiration::After(ttl) => Some(now + ttl.as_millis() as u64),
+                    Expiration::At(time) => Some(time),
+                    Expiration::Keep => old.filter(|f|!f.is_expired(now)).and_then(|f|f.expires_at)
+                };
+                Some(File { version, data: kv.value, expires_at, modified, metadata })
             } else {
-                self.delete(kv.key)?;
-                self.data.remove(&kv.key);
+                None
+            };
+            match self.replace(kv.key, file) {
+                Ok(old) => changes.files.push((kv.key, old)),
+                Err(e) => {
+                    self.revert(changes)?;
+                    return Err(e);
+                }
             }
         }
-        Ok(())
+        Ok(changes)
+    }
+
+    // saves or deletes (file = None) key file, returns previous file
+    fn replace(&mut self, key: u64, file: Option<File>) -> Result<Option<File>, Error> {
+        let old = match file {
+            Some(file) => {
+                self.save(key, &file)?;
+                self.counters.add(&file);
+                self.data.insert(key, file)
+            }
+            None => {
+                self.delete(key)?;
+                self.claims.remove(&key);
+                self.data.remove(&key)
+            }
+        };
+        if let Some(old) = &old {
+            self.counters.remove(old);
+        }
+        Ok(old)
     }
     
-    pub fn get_file_version(&self, key: usize) -> Option<u32> {
-        self.data.get(&key).map(|f|f.version)
+    // assigns key = last key in range [key1, key2] + 1 or key1 when range is empty
+    pub fn append(&mut self, key1: u64, key2: u64, value: Vec<u8>, user_id: u32) -> Result<u64, Error> {
+        if value.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "empty value"));
+        }
+        if key1 > key2 {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid key range"));
+        }
+        let key = match self.range(key1..=key2).next_back() {
+            Some((k, _)) => if *k == key2 {
+                return Err(Error::new(ErrorKind::InvalidInput, "key range is full"));
+            } else {*k + 1},
+            None => key1
+        };
+        self.set(self.version, vec![KeyValue{key, version: 0, value}], user_id)?;
+        Ok(key)
     }
 
-    fn delete(&self, key: usize) -> Result<(), Error> {
-        let file_path = self.build_file_path(key)?;
-        if file_path.try_exists()? {
-            fs::remove_file(file_path)
-        } else {
-            Ok(())
+    // writes bytes at given offset of the key value, appends them when offset is None
+    pub fn write_value(&mut self, expected_version: u32, key: u64, offset: Option<usize>, bytes: &[u8],
+                       user_id: u32) -> Result<(), Error> {
+        if bytes.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "empty value"));
+        }
+        let mut value = self.get_file(key).map(|f|f.data.clone()).unwrap_or_default();
+        let offset = offset.unwrap_or(value.len());
+        if offset > value.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "offset is out of range"));
         }
+        let end = offset + bytes.len();
+        if end > value.len() {
+            value.resize(end, 0);
+        }
+        value[offset..end].copy_from_slice(bytes);
+        self.update(expected_version, key, value, user_id)
     }
 
-    fn save(&self, key: usize, version: u32, value: &Vec<u8>) -> Result<(), Error> {
-        let file_path = self.build_file_path(key)?;
-        let mut file = fs::File::create(file_path)?;
-        file.write(&version.to_le_bytes())?;
-        file.write(value)?;
-        Ok(())
+    // rebuilds key value from patch against base file version, see apply_patch
+    pub fn patch(&mut self, expected_version: u32, key: u64, base_version: u32, patch: &[u8], user_id: u32)
+        -> Result<(), Error> {
+        let (version, base) = self.get_file(key)
+            .map(|f|(f.version, f.data.as_slice()))
+            .unwrap_or((0, &[]));
+        if version != base_version {
+            return Err(Error::new(ErrorKind::InvalidData, "file version mismatch"));
+        }
+        let value = apply_patch(base, patch, MAX_PATCHED_VALUE_SIZE)?;
+        self.update(expected_version, key, value, user_id)
+    }
+
+    // value is stored as 64 bit little endian integer, missing key is treated as 0
+    pub fn increment(&mut self, key: u64, delta: i64, user_id: u32) -> Result<i64, Error> {
+        let value = match self.get_file(key) {
+            Some(f) => i64::from_le_bytes(f.data.as_slice().try_into()
+                .map_err(|_|Error::new(ErrorKind::InvalidData, "value is not a 64 bit integer"))?),
+            None => 0
+        };
+        let new_value = value.checked_add(delta)
+            .ok_or(Error::new(ErrorKind::InvalidInput, "counter overflow"))?;
+        self.update(self.version, key, new_value.to_le_bytes().to_vec(), user_id)?;
+        Ok(new_value)
     }
 
-    fn build_file_path(&self, key: usize) -> Result<PathBuf, Error> {
-        let folder_name = (key / self.hash_divider).to_string();
-        let folder = self.base_folder.join(folder_name);
-        if !fs::exists(&folder)? {
-            fs::create_dir(&folder)?;
+    // removes first (or last) not claimed key in range [key1, key2]
+    // or claims it for claim_timeout when it is specified
+    pub fn pop(&mut self, key1: u64, key2: u64, last: bool, claim_timeout: Option<Duration>)
+        -> Result<Option<KeyValue>, Error> {
+        let now = Instant::now();
+        // expired claims are removed here, so claims map does not grow
+        self.claims.retain(|_, (deadline, _)|*deadline > now);
+        // key changed after claim holds new value, which is not claimed
+        let is_free = |k: &u64, f: &File| self.claims.get(k).is_none_or(|(_, version)|*version != f.version);
+        let kv = {
+            let mut range = self.range(key1..=key2);
+            let item = if last {
+                range.rfind(|(k, f)|is_free(k, f))
+            } else {
+                range.find(|(k, f)|is_free(k, f))
+            };
+            match item {
+                Some((k, value)) => KeyValue{key: *k, version: value.version, value: value.data.clone()},
+                None => return Ok(None)
+            }
         };
-        Ok(folder.join(key.to_string()))
+        match claim_timeout {
+            Some(timeout) => {
+                self.claims.insert(kv.key, (now + timeout, kv.version));
+            },
+            None => self.remove_keys(vec![kv.key])?
+        }
+        Ok(Some(kv))
+    }
+
+    // removes claimed key, claim must not be expired:
+    // after claim timeout the key can be popped by another worker, so late ack is rejected.
+    // key changed after claim is not removed, its claim is released
+    pub fn ack(&mut self, key: u64) -> Result<(), Error> {
+        match self.claims.get(&key) {
+            Some((deadline, version)) if *deadline > Instant::now() => {
+                if self.get_file_version(key) != Some(*version) {
+                    self.claims.remove(&key);
+                    return Err(Error::new(ErrorKind::InvalidData, "key was changed after claim"));
+                }
+                self.remove_keys(vec![key])
+            }
+            _ => Err(Error::new(ErrorKind::InvalidInput, "key is not claimed"))
+        }
+    }
+
+    // releases claimed key
+    pub fn nack(&mut self, key: u64) -> Result<(), Error> {
+        match self.claims.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(Error::new(ErrorKind::InvalidInput, "key is not claimed"))
+        }
+    }
+
+    pub fn get_file_version(&self, key: u64) -> Option<u32> {
+        self.get_file(key).map(|f|f.version)
+    }
+
+    pub fn get_expired_keys(&self) -> Vec<u64> {
+        let now = unix_time_ms();
+        self.data.iter()
+            .filter(|(_, f)|f.is_expired(now))
+            .map(|(k, _)|*k)
+            .collect()
+    }
+
+    // keys are found by get_expired_keys, possibly under another lock, so they are checked again
+    pub fn remove_expired(&mut self, keys: Vec<u64>) -> Result<usize, Error> {
+        let now = unix_time_ms();
+        let expired: Vec<u64> = keys.into_iter()
+            .filter(|k|self.data.get(k).is_some_and(|f|f.is_expired(now)))
+            .collect();
+        let count = expired.len();
+        if count != 0 {
+            self.remove_keys(expired)?;
+        }
+        Ok(count)
+    }
+
+    // deletions do not store user id
+    pub fn remove_keys(&mut self, keys: Vec<u64>) -> Result<(), Error> {
+        let data = keys.into_iter().map(|key|KeyValue{key, version: 0, value: Vec::new()}).collect();
+        self.set(self.version, data, 0)
+    }
+
+    // expired keys are hidden until they are removed by remove_expired
+    // range with start > end is empty (BTreeMap::range panics on it)
+    fn range<R: RangeBounds<u64>>(&self, range: R) -> impl DoubleEndedIterator<Item = (&u64, &File)> {
+        let now = unix_time_ms();
+        let empty = match (range.start_bound(), range.end_bound()) {
+            (Excluded(start), Excluded(end)) => start >= end,
+            (Included(start) | Excluded(start), Included(end) | Excluded(end)) => start > end,
+            _ => false
+        };
+        let bounds = if empty {
+            (Included(0), Excluded(0))
+        } else {
+            (range.start_bound().cloned(), range.end_bound().cloned())
+        };
+        self.data.range(bounds).filter(move |(_, f)|!f.is_expired(now))
+    }
+
+    fn get_file(&self, key: u64) -> Option<&File> {
+        self.data.get(&key).filter(|f|!f.is_expired(unix_time_ms()))
+    }
+
+    fn delete(&self, key: u64) -> Result<(), Error> {
+        delete_file(&self.base_folder.join(folder_name(key, self.hash_divider)), &key.to_string())
+    }
+
+    fn save(&self, key: u64, file: &File) -> Result<(), Error> {
+        save_file(&self.base_folder.join(folder_name(key, self.hash_divider)), &key.to_string(), file)
+    }
+}
+
+// patch is a list of operations:
+// 0, offset: u32, length: u32 - copy bytes from base value
+// 1, length: u32, bytes - insert bytes
+// result longer than max_size is rejected
+fn apply_patch(base: &[u8], patch: &[u8], max_size: usize) -> Result<Vec<u8>, Error> {
+    let l = patch.len();
+    let mut idx = 0;
+    let mut result = Vec::new();
+    while idx < l {
+        match patch[idx] {
+            0 => {
+                if l < idx + 9 {
+                    return Err(Error::new(ErrorKind::InvalidInput, "patch is too short"));
+                }
+                let offset = u32::from_le_bytes(patch[idx+1..idx+5].try_into().unwrap()) as usize;
+                let length = u32::from_le_bytes(patch[idx+5..idx+9].try_into().unwrap()) as usize;
+                if offset + length > base.len() {
+                    return Err(Error::new(ErrorKind::InvalidInput, "patch copy operation is out of range"));
+                }
+                if result.len() + length > max_size {
+                    return Err(Error::new(ErrorKind::InvalidInput, "patched value is too long"));
+                }
+                result.extend_from_slice(&base[offset..offset+length]);
+                idx += 9;
+            },
+            1 => {
+                if l < idx + 5 {
+                    return Err(Error::new(ErrorKind::InvalidInput, "patch is too short"));
+                }
+                let length = u32::from_le_bytes(patch[idx+1..idx+5].try_into().unwrap()) as usize;
+                idx += 5;
+                if l < idx + length {
+                    return Err(Error::new(ErrorKind::InvalidInput, "patch is too short"));
+                }
+                if result.len() + length > max_size {
+                    return Err(Error::new(ErrorKind::InvalidInput, "patched value is too long"));
+                }
+                result.extend_from_slice(&patch[idx..idx+length]);
+                idx += length;
+            },
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "invalid patch operation"))
+        }
+    }
+    if result.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "empty value"));
+    }
+    Ok(result)
+}
+
+// count: u8, for each attribute: name length: u8, name, value length: u32, value
+pub fn encode_metadata(metadata: &Metadata, data: &mut Vec<u8>) {
+    data.push(metadata.len() as u8);
+    for (name, value) in metadata {
+        data.push(name.len() as u8);
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        data.extend_from_slice(value);
+    }
+}
+
+// returns metadata and number of bytes used
+pub fn decode_metadata(data: &[u8]) -> Result<(Metadata, usize), Error> {
+    let l = data.len();
+    if l < 1 {
+        return Err(Error::new(ErrorKind::InvalidInput, "metadata is too short"));
+    }
+    let mut count = data[0];
+    let mut idx = 1;
+    let mut result = Metadata::new();
+    while count > 0 {
+        if l < idx + 1 {
+            return Err(Error::new(ErrorKind::InvalidInput, "metadata is too short"));
+        }
+        let name_length = data[idx] as usize;
+        idx += 1;
+        if l < idx + name_length + 4 {
+            return Err(Error::new(ErrorKind::InvalidInput, "metadata is too short"));
+        }
+        let name = String::from_utf8(data[idx..idx+name_length].to_vec())
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        idx += name_length;
+        let value_length = u32::from_le_bytes(data[idx..idx+4].try_into().unwrap()) as usize;
+        idx += 4;
+        if l < idx + value_length {
+            return Err(Error::new(ErrorKind::InvalidInput, "metadata is too short"));
+        }
+        result.insert(name, data[idx..idx+value_length].to_vec());
+        idx += value_length;
+        count -= 1;
+    }
+    Ok((result, idx))
+}
+
+pub fn key_length(wide_keys: bool) -> usize {
+    if wide_keys {8} else {4}
+}
+
+// reads u32 or u64 little endian key from the start of data
+pub fn read_key(data: &[u8], wide_keys: bool) -> u64 {
+    if wide_keys {
+        u64::from_le_bytes(data[0..8].try_into().unwrap())
+    } else {
+        u32::from_le_bytes(data[0..4].try_into().unwrap()) as u64
+    }
+}
+
+pub fn write_key(data: &mut Vec<u8>, key: u64, wide_keys: bool) {
+    if wide_keys {
+        data.extend_from_slice(&key.to_le_bytes());
+    } else {
+        data.extend_from_slice(&(key as u32).to_le_bytes());
     }
 }
 
-fn load_data(base_folder: &PathBuf) -> Result<BTreeMap<usize, File>, Error> {
-    let contents = fs::read_dir(base_folder)?;
+// (u32, u32) composite key, ordered by first component, then by second one
+pub fn composite_key(first: u64, second: u64) -> u64 {
+    (first << 32) | second
+}
+
+pub fn split_composite_key(key: u64) -> (u64, u64) {
+    (key >> 32, key & 0xFFFFFFFF)
+}
+
+// keys below hash_divider * MAX_FOLDERS are stored in the same folders as before the limit
+fn folder_name(key: u64, hash_divider: u64) -> String {
+    ((key / hash_divider) % MAX_FOLDERS).to_string()
+}
+
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+// files stored in another folder (by older versions) are moved to folder_name folder
+fn load_data(base_folder: &Path, hash_divider: u64) -> Result<BTreeMap<u64, File>, Error> {
     let mut result = BTreeMap::new();
-    for entry_result in contents {
+    load_files(base_folder, |folder, name, path, file|{
+        let key = name.parse::<u64>().map_err(|e|Error::new(ErrorKind::InvalidData, e))?;
+        let expected_folder = folder_name(key, hash_divider);
+        if folder != expected_folder {
+            let expected_folder = base_folder.join(expected_folder);
+            create_folder(&expected_folder)?;
+            fs::rename(path, expected_folder.join(name))?;
+        }
+        result.insert(key, file);
+        Ok(())
+    })?;
+    Ok(result)
+}
+
+// database files are stored in base folder subfolders,
+// handler receives folder name, file name, file path and file contents
+pub fn load_files(base_folder: &Path, mut handler: impl FnMut(&str, &str, &Path, File) -> Result<(), Error>)
+    -> Result<(), Error> {
+    for entry_result in fs::read_dir(base_folder)? {
         let entry = entry_result?;
         if entry.file_type()?.is_dir() {
-            let files = fs::read_dir(entry.path())?;
-            for file_result in files {
+            let folder = entry.file_name().into_string().unwrap();
+            for file_result in fs::read_dir(entry.path())? {
                 let file = file_result?;
                 if file.file_type()?.is_file() {
-                    let key = file.file_name().into_string().unwrap().parse::<usize>()
-                        .map_err(|e|Error::new(ErrorKind::InvalidData, e))?;
                     let data = fs::read(file.path())?;
-                    let version = u32::from_le_bytes(data[0..4].try_into().unwrap());
-                    result.insert(key, File{version, data: data[4..].to_vec()});
+                    let mut f = File::from_binary(&data)?;
+                    if f.modified.time == 0 {
+                        // file was saved without modification time
+                        f.modified.time = file.metadata()?.modified()?.duration_since(UNIX_EPOCH)
+                            .map(|d|d.as_millis() as u64).unwrap_or(0);
+                    }
+                    handler(&folder, &file.file_name().into_string().unwrap(), &file.path(), f)?;
                 }
             }
         }
     }
-    Ok(result)
+    Ok(())
+}
+
+pub fn save_file(folder: &Path, name: &str, file: &File) -> Result<(), Error> {
+    create_folder(folder)?;
+    fs::write(folder.join(name), file.to_binary())
+}
+
+pub fn delete_file(folder: &Path, name: &str) -> Result<(), Error> {
+    let file_path = folder.join(name);
+    if file_path.try_exists()? {
+        fs::remove_file(file_path)
+    } else {
+        Ok(())
+    }
+}
+
+fn create_folder(folder: &Path) -> Result<(), Error> {
+    if !fs::exists(folder)? {
+        fs::create_dir(folder)?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -164,9 +965,11 @@ mod tests {
     use std::{fs, panic};
     use std::io::Error;
     use std::path::PathBuf;
+    use std::time::Duration;
     use rand::rngs::OsRng;
     use rand::TryRngCore;
-    use crate::database::{Database, KeyValue};
+    use crate::database::{apply_patch, composite_key, MAX_FOLDERS, decode_metadata, encode_metadata, split_composite_key, Database, File,
+                          KeyValue, Metadata, Modification, Navigation, ScanOptions};
 
     const TEST_DB_PATH: &str = "/mnt/ramdisk/test_database";
 
@@ -180,20 +983,358 @@ mod tests {
         fs::create_dir(TEST_DB_PATH)?;
         let mut database = Database::new(PathBuf::from(TEST_DB_PATH), 10000)?;
         let mut files = build_files(1000)?;
-        database.set(1, files.clone())?;
+        database.set(1, files.clone(), 1)?;
         compare_database(&database, &files);
         let mut set = modify_files(&mut files, 100);
         //delete operation
         set[0].value = Vec::new();
         let idx = files.iter().position(|f|f.key == set[0].key).unwrap();
         files.remove(idx);
-        database.set(2, set)?;
+        database.set(2, set, 1)?;
         compare_database(&database, &files);
         let database2 = Database::new(PathBuf::from(TEST_DB_PATH), 10000)?;
         compare_database(&database2, &files);
         fs::remove_dir_all(TEST_DB_PATH)
     }
 
+    #[test]
+    fn test_get_changed() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_changed";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        database.set(1, vec![KeyValue{key: 1, version: 0, value: vec![1]},
+                             KeyValue{key: 2, version: 0, value: vec![2]},
+                             KeyValue{key: 3, version: 0, value: vec![3]}], 1)?;
+        database.set(2, vec![KeyValue{key: 2, version: 0, value: vec![4]},
+                             KeyValue{key: 3, version: 0, value: Vec::new()}], 1)?;
+        let changed = database.get_changed_keys(vec![(1, 1), (2, 1), (3, 1), (4, 0)]);
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[0].0, 2);
+        assert_eq!(changed[0].1.as_ref().unwrap().version, 2);
+        assert_eq!(changed[1].0, 3);
+        assert!(changed[1].1.is_none());
+        let versions = HashMap::from([(2, 2), (3, 1), (10, 1)]);
+        let changed = database.get_changed_range(0, 5, &versions);
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[0].0, 1);
+        assert_eq!(changed[0].1.as_ref().unwrap().value, &vec![1]);
+        assert_eq!(changed[1].0, 3);
+        assert!(changed[1].1.is_none());
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_append() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_append";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        assert_eq!(database.append(100, 101, vec![1], 1)?, 100);
+        assert_eq!(database.append(100, 101, vec![2], 1)?, 101);
+        assert!(database.append(100, 101, vec![3], 1).is_err());
+        assert!(database.append(200, 300, Vec::new(), 1).is_err());
+        assert!(database.append(300, 200, vec![3], 1).is_err());
+        assert_eq!(database.get_version(), 3);
+        assert_eq!(database.get_key(101).unwrap().value, &vec![2]);
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_ttl() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_ttl";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        database.set(1, vec![KeyValue{key: 1, version: 0, value: vec![1]}], 1)?;
+        database.set_with_ttl(2, vec![KeyValue{key: 2, version: 0, value: vec![2]}], 1,
+                              Some(Duration::from_millis(200)))?;
+        database.set_with_ttl(3, vec![KeyValue{key: 3, version: 0, value: vec![3]}], 1,
+                              Some(Duration::from_secs(60)))?;
+        let database2 = Database::new(PathBuf::from(path), 10000)?;
+        assert_eq!(database2.get(0, 10, &ScanOptions::default()).len(), 3);
+        assert_eq!(database2.get_key(2).unwrap().value, &vec![2]);
+        // partial updates keep ttl
+        database.set_with_ttl(4, vec![KeyValue{key: 4, version: 0, value: 0i64.to_le_bytes().to_vec()}], 1,
+                              Some(Duration::from_millis(200)))?;
+        database.increment(4, 1, 1)?;
+        database.write_value(6, 2, Some(0), &[5], 1)?;
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(database.get_file_version(2).is_none());
+        assert!(database.get_file_version(4).is_none());
+        assert_eq!(database.get(0, 10, &ScanOptions::default()).len(), 2);
+        assert_eq!(database.get_version(), 7);
+        let expired = database.get_expired_keys();
+        assert_eq!(expired, vec![2, 4]);
+        assert_eq!(database.remove_expired(vec![1, 2, 3, 4])?, 2);
+        assert_eq!(database.remove_expired(expired)?, 0);
+        assert_eq!(database.get_version(), 8);
+        let database2 = Database::new(PathBuf::from(path), 10000)?;
+        assert_eq!(database2.get(0, 10, &ScanOptions::default()).len(), 2);
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_modified_since() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_modified";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        database.set(1, vec![KeyValue{key: 1, version: 0, value: vec![1]},
+                             KeyValue{key: 2, version: 0, value: vec![2]}], 5)?;
+        let since = database.get_key(1).unwrap().modified.time;
+        std::thread::sleep(Duration::from_millis(10));
+        database.set(2, vec![KeyValue{key: 2, version: 0, value: vec![3]}], 7)?;
+        let modified = database.get_modified_since(0, 10, since);
+        assert_eq!(modified.len(), 1);
+        assert_eq!(modified[0].key, 2);
+        assert_eq!(modified[0].modified.user_id, 7);
+        let database2 = Database::new(PathBuf::from(path), 10000)?;
+        let kv = database2.get_key(1).unwrap();
+        assert_eq!(kv.modified.time, since);
+        assert_eq!(kv.modified.user_id, 5);
+        assert_eq!(database2.get_modified_since(0, 10, since).len(), 1);
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_revert() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_revert";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        database.set(1, vec![KeyValue{key: 1, version: 0, value: vec![1]},
+                             KeyValue{key: 2, version: 0, value: vec![2]}], 1)?;
+        let changes = database.set_revertible(2, vec![KeyValue{key: 1, version: 0, value: vec![3]},
+                                                      KeyValue{key: 2, version: 0, value: Vec::new()},
+                                                      KeyValue{key: 3, version: 0, value: vec![4]}], 1)?;
+        assert_eq!(database.get_version(), 3);
+        database.revert(changes)?;
+        assert_eq!(database.get_version(), 2);
+        assert!(database.set_revertible(3, Vec::new(), 1).is_err());
+        let database2 = Database::new(PathBuf::from(path), 10000)?;
+        for db in [&database, &database2] {
+            let kv = db.get_key(1).unwrap();
+            assert_eq!((kv.version, kv.value.clone()), (1, vec![1]));
+            assert_eq!(db.get_key(2).unwrap().value, &vec![2]);
+            assert!(db.get_key(3).is_none());
+            assert_eq!(db.get_statistics().total_size, 2);
+        }
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_key_value_from() -> Result<(), Error> {
+        let data = vec![KeyValue{key: 1, version: 0, value: vec![1, 2]}, KeyValue{key: 5, version: 0, value: vec![3]}];
+        let mut binary = 2u32.to_le_bytes().to_vec();
+        for kv in &data {
+            binary.extend_from_slice(&kv.key.to_le_bytes());
+            binary.extend_from_slice(&(kv.value.len() as u32).to_le_bytes());
+            binary.extend_from_slice(&kv.value);
+        }
+        let result = KeyValue::from(&binary, true)?;
+        assert_eq!(result.len(), 2);
+        assert_eq!((result[1].key, result[1].value.clone()), (5, vec![3]));
+        assert!(KeyValue::from(&binary[..binary.len() - 1], true).is_err());
+        assert!(KeyValue::from(&binary[..binary.len() - 6], true).is_err());
+        assert!(KeyValue::from(&binary, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_metadata";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        let metadata1 = Metadata::from([("type".to_string(), b"text".to_vec()), ("name".to_string(), vec![1])]);
+        let metadata2 = Metadata::from([("type".to_string(), b"image".to_vec())]);
+        database.set_with_metadata(1, vec![(KeyValue{key: 1, version: 0, value: vec![1]}, metadata1.clone()),
+                                           (KeyValue{key: 2, version: 0, value: vec![2]}, metadata2)], 1)?;
+        database.set(2, vec![KeyValue{key: 1, version: 0, value: vec![3]}], 1)?;
+        assert_eq!(database.get_key(1).unwrap().metadata, &metadata1);
+        let found = database.find_by_attribute(0, 10, "type", b"image");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].key, 2);
+        let database2 = Database::new(PathBuf::from(path), 10000)?;
+        assert_eq!(database2.get_key(1).unwrap().metadata, &metadata1);
+        assert_eq!(database2.find_by_attribute(0, 10, "type", b"text").len(), 1);
+        let mut data = Vec::new();
+        encode_metadata(&metadata1, &mut data);
+        assert_eq!(decode_metadata(&data)?, (metadata1.clone(), data.len()));
+        assert!(decode_metadata(&data[..data.len() - 1]).is_err());
+        let file = File{version: 1, data: vec![1, 2], expires_at: Some(1), modified: Modification{time: 1, user_id: 1},
+                        metadata: metadata1};
+        assert_eq!(file.binary_size(), file.to_binary().len() as u64);
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_write_value() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_write_value";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        database.write_value(1, 1, None, &[1, 2], 1)?;
+        database.write_value(2, 1, None, &[3], 1)?;
+        database.write_value(3, 1, Some(1), &[4, 5, 6], 1)?;
+        assert_eq!(database.get_key(1).unwrap().value, &vec![1, 4, 5, 6]);
+        assert_eq!(database.get_file_version(1), Some(3));
+        assert!(database.write_value(4, 1, Some(5), &[7], 1).is_err());
+        assert!(database.write_value(3, 1, None, &[7], 1).is_err());
+        assert!(database.write_value(4, 2, Some(1), &[7], 1).is_err());
+        assert_eq!(database.get_version(), 4);
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_patch() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_patch";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        database.set(1, vec![KeyValue{key: 1, version: 0, value: vec![1, 2, 3, 4, 5]}], 1)?;
+        // copy [3, 4, 5], insert [9], copy [1]
+        let patch = [0, 2, 0, 0, 0, 3, 0, 0, 0, 1, 1, 0, 0, 0, 9, 0, 0, 0, 0, 0, 1, 0, 0, 0];
+        assert!(database.patch(2, 1, 0, &patch, 1).is_err());
+        assert!(database.patch(2, 1, 1, &patch[..10], 1).is_err());
+        database.patch(2, 1, 1, &patch, 1)?;
+        assert_eq!(database.get_key(1).unwrap().value, &vec![3, 4, 5, 9, 1]);
+        assert_eq!(database.get_file_version(1), Some(2));
+        assert!(database.patch(3, 1, 2, &[0, 4, 0, 0, 0, 2, 0, 0, 0], 1).is_err());
+        assert_eq!(database.get_version(), 3);
+        let copy_all = [0, 0, 0, 0, 0, 5, 0, 0, 0];
+        assert_eq!(apply_patch(&[1, 2, 3, 4, 5], &copy_all.repeat(2), 10)?.len(), 10);
+        assert!(apply_patch(&[1, 2, 3, 4, 5], &copy_all.repeat(3), 10).is_err());
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_increment() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_increment";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        assert_eq!(database.increment(1, 5, 1)?, 5);
+        assert_eq!(database.increment(1, -7, 1)?, -2);
+        assert_eq!(database.get_file_version(1), Some(2));
+        database.set(3, vec![KeyValue{key: 2, version: 0, value: vec![1, 2, 3]}], 1)?;
+        assert!(database.increment(2, 1, 1).is_err());
+        assert!(database.increment(1, i64::MIN, 1).is_err());
+        assert_eq!(database.get_version(), 4);
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_pop() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_pop";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        database.set(1, vec![KeyValue{key: 1, version: 0, value: vec![1]},
+                             KeyValue{key: 2, version: 0, value: vec![2]},
+                             KeyValue{key: 3, version: 0, value: vec![3]},
+                             KeyValue{key: 4, version: 0, value: vec![4]}], 1)?;
+        assert_eq!(database.pop(0, 10, false, None)?.unwrap().key, 1);
+        assert_eq!(database.pop(0, 10, true, None)?.unwrap().key, 4);
+        assert_eq!(database.get_version(), 4);
+        assert_eq!(database.pop(0, 10, false, Some(Duration::from_secs(60)))?.unwrap().key, 2);
+        assert_eq!(database.pop(0, 10, false, Some(Duration::from_secs(60)))?.unwrap().key, 3);
+        assert!(database.pop(0, 10, false, None)?.is_none());
+        assert_eq!(database.get_version(), 4);
+        database.nack(2)?;
+        database.ack(3)?;
+        assert!(database.ack(3).is_err());
+        assert_eq!(database.get_version(), 5);
+        assert_eq!(database.pop(0, 10, true, None)?.unwrap().key, 2);
+        assert!(database.pop(0, 10, true, None)?.is_none());
+        assert!(database.pop(10, 5, false, None)?.is_none());
+        database.set(6, vec![KeyValue{key: 1, version: 0, value: vec![1]}], 1)?;
+        assert_eq!(database.pop(0, 10, false, Some(Duration::from_millis(10)))?.unwrap().key, 1);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(database.ack(1).is_err());
+        assert_eq!(database.pop(0, 10, false, Some(Duration::from_secs(60)))?.unwrap().key, 1);
+        assert_eq!(database.claims.len(), 1);
+        assert_eq!(database.get_range_statistics(10, 5).count, 0);
+        // key changed after claim is not removed by ack and can be popped again
+        let version = database.get_version();
+        database.set(version, vec![KeyValue{key: 1, version: 0, value: vec![5]}], 1)?;
+        let kv = database.pop(0, 10, false, Some(Duration::from_secs(60)))?.unwrap();
+        assert_eq!((kv.key, kv.value), (1, vec![5]));
+        database.set(version + 1, vec![KeyValue{key: 1, version: 0, value: vec![6]}], 1)?;
+        assert!(database.ack(1).is_err());
+        assert_eq!(database.get_key(1).unwrap().value, &vec![6]);
+        assert!(database.ack(1).is_err());
+        assert!(database.nack(1).is_err());
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_navigate() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_navigate";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        database.set(1, vec![KeyValue{key: 10, version: 0, value: vec![1]},
+                             KeyValue{key: 20, version: 0, value: vec![2]},
+                             KeyValue{key: 30, version: 0, value: vec![3]}], 1)?;
+        let keys: Vec<u64> = database.get(0, 100, &ScanOptions{descending: true, step: 1, limit: 2})
+            .iter().map(|kv|kv.key).collect();
+        assert_eq!(keys, vec![30, 20]);
+        let keys: Vec<u64> = database.get(0, 100, &ScanOptions{descending: false, step: 2, limit: usize::MAX})
+            .iter().map(|kv|kv.key).collect();
+        assert_eq!(keys, vec![10, 30]);
+        assert_eq!(database.get_first(15, 100).unwrap().key, 20);
+        assert!(database.get_first(31, 100).is_none());
+        assert_eq!(database.navigate(20, Navigation::Floor).unwrap().key, 20);
+        assert_eq!(database.navigate(25, Navigation::Floor).unwrap().key, 20);
+        assert!(database.navigate(5, Navigation::Floor).is_none());
+        assert_eq!(database.navigate(20, Navigation::Ceiling).unwrap().key, 20);
+        assert_eq!(database.navigate(25, Navigation::Ceiling).unwrap().key, 30);
+        assert_eq!(database.navigate(20, Navigation::Next).unwrap().key, 30);
+        assert!(database.navigate(30, Navigation::Next).is_none());
+        assert_eq!(database.navigate(20, Navigation::Previous).unwrap().key, 10);
+        assert!(database.navigate(10, Navigation::Previous).is_none());
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_statistics() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_statistics";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        database.set(1, vec![KeyValue{key: 1, version: 0, value: vec![1; 3]},
+                             KeyValue{key: 5, version: 0, value: vec![2; 10]},
+                             KeyValue{key: 9, version: 0, value: vec![3; 10]}], 1)?;
+        database.set(2, vec![KeyValue{key: 5, version: 0, value: vec![4]},
+                             KeyValue{key: 1, version: 0, value: Vec::new()}], 1)?;
+        let statistics = database.get_statistics();
+        assert_eq!(statistics.count, 2);
+        assert_eq!(statistics.total_size, 11);
+        assert_eq!((statistics.min_key, statistics.max_key), (5, 9));
+        assert_eq!(statistics.version, 3);
+        assert_eq!(statistics.max_value_size, 10);
+        let database2 = Database::new(PathBuf::from(path), 10000)?;
+        assert_eq!(database2.get_statistics().disk_size, statistics.disk_size);
+        assert_eq!(statistics.disk_size, fs::metadata(format!("{}/0/5", path))?.len() +
+            fs::metadata(format!("{}/0/9", path))?.len());
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_composite_keys() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_database_composite";
+        fs::create_dir(path)?;
+        let mut database = Database::new(PathBuf::from(path), 10000)?;
+        database.set(1, vec![KeyValue{key: composite_key(7, 100), version: 0, value: vec![1]},
+                             KeyValue{key: composite_key(7, u32::MAX as u64), version: 0, value: vec![2]},
+                             KeyValue{key: composite_key(8, 0), version: 0, value: vec![3]}], 1)?;
+        let last = database.get_last(composite_key(7, 0), composite_key(7, u32::MAX as u64)).unwrap();
+        assert_eq!(split_composite_key(last.key), (7, u32::MAX as u64));
+        let keys: Vec<(u64, u64)> = database.get(composite_key(8, 0), composite_key(8, 10), &ScanOptions::default())
+            .iter().map(|kv|split_composite_key(kv.key)).collect();
+        assert_eq!(keys, vec![(8, 0)]);
+        assert!(fs::exists(format!("{}/{}", path, (composite_key(7, 100) / 10000) % MAX_FOLDERS))?);
+        // file saved in folder key / hash_divider is moved to bounded folder on load
+        let key = 10000 * MAX_FOLDERS + 5;
+        fs::create_dir(format!("{}/{}", path, MAX_FOLDERS))?;
+        fs::write(format!("{}/{}/{}", path, MAX_FOLDERS, key), 1u32.to_le_bytes())?;
+        let database2 = Database::new(PathBuf::from(path), 10000)?;
+        assert_eq!(database2.get_file_version(key), Some(1));
+        assert!(fs::exists(format!("{}/0/{}", path, key))?);
+        assert!(!fs::exists(format!("{}/{}/{}", path, MAX_FOLDERS, key))?);
+        fs::remove_dir_all(path)
+    }
+
     fn modify_files(files: &mut Vec<KeyValue>, mut count: usize) -> Vec<KeyValue> {
         let mut result = Vec::new();
         let mut key_set: HashSet<usize> = HashSet::new();
@@ -212,8 +1353,8 @@ mod tests {
     }
 
     fn compare_database(database: &Database, files: &Vec<KeyValue>) {
-        let files_map: HashMap<usize, KeyValue> = files.iter().map(|f|(f.key, f.clone())).collect();
-        let data = database.get(0, usize::MAX);
+        let files_map: HashMap<u64, KeyValue> = files.iter().map(|f|(f.key, f.clone())).collect();
+        let data = database.get(0, u64::MAX, &ScanOptions::default());
         assert_eq!(data.len(), files.len());
         for item in data {
             let file_option = files_map.get(&item.key);
@@ -236,11 +1377,11 @@ mod tests {
     
     fn build_files(mut number_of_files: usize) -> Result<Vec<KeyValue>, Error> {
         let mut result = Vec::new();
-        let mut key_set: HashSet<usize> = HashSet::new();
+        let mut key_set: HashSet<u64> = HashSet::new();
         while number_of_files > 0 {
-            let mut key = OsRng.try_next_u32().unwrap() as usize;
+            let mut key = OsRng.try_next_u64().unwrap();
             while key_set.contains(&key) {
-                key = OsRng.try_next_u32().unwrap() as usize;
+                key = OsRng.try_next_u64().unwrap();
             }
             key_set.insert(key);
             let data= build_random_data();
@@ -1,83 +1,1262 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::Error;
-use std::path::PathBuf;
-use std::sync::RwLock;
-use crate::database::{Database, KeyValue};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use std::thread;
+use std::time::Duration;
+use crate::byte_key_database::{ByteKeyDatabase, ByteKeyValue};
+use crate::database::{unix_time_ms, Database, DatabaseStatistics, KeyValue, Metadata, Modification, Navigation, RangeStatistics, ScanOptions};
 
+const EXPIRATION_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+// dropped databases are moved here and deleted in background
+const TRASH_FOLDER_NAME: &str = ".trash";
+// database folder is moved here during swap, interrupted swap is completed at startup
+const SWAP_FOLDER_NAME: &str = ".swap";
+// per database settings file, stored in database folder
+const SETTINGS_FILE_NAME: &str = "settings";
+
+// map lock is held only for name lookups and name changes, every database has its own lock.
+// lock order is database locks (in address order) first, then map lock
 pub struct Databases {
     base_path: PathBuf,
-    hash_divider: usize,
-    data: HashMap<String, RwLock<Database>>
+    hash_divider: u64,
+    data: RwLock<HashMap<String, OpenDatabase>>,
+    // names of databases declared with byte string keys
+    byte_key_databases: HashSet<String>,
+    // false - set commands do not create missing databases
+    implicit_creation: bool
+}
+
+// key, file version, value length, modification
+pub type KeyVersion = (u64, u32, usize, Modification);
+// key, value (None - key was deleted)
+pub type ChangedKey = (u64, Option<KeyValue>);
+// file version, full value length, value part
+pub type ValuePart = (u32, usize, Vec<u8>);
+
+pub struct TransactionGroup {
+    pub database: String,
+    pub expected_version: u32,
+    pub data: Vec<KeyValue>,
+}
+
+#[derive(Clone)]
+enum OpenDatabase {
+    Integer(Arc<RwLock<Database>>),
+    ByteKey(Arc<RwLock<ByteKeyDatabase>>)
+}
+
+// write lock of any database type, used by commands that change database names
+enum OpenDatabaseLock<'a> {
+    Integer(RwLockWriteGuard<'a, Database>),
+    ByteKey(RwLockWriteGuard<'a, ByteKeyDatabase>)
+}
+
+// database types stored in databases map
+trait StoredDatabase: Sized {
+    const BYTE_KEYS: bool;
+    // error message when database with the same name has another keys type
+    const WRONG_KEY_TYPE: &'static str;
+
+    fn open(path: PathBuf, hash_divider: u64) -> Result<Self, Error>;
+    fn wrap(db: Arc<RwLock<Self>>) -> OpenDatabase;
+    fn unwrap(db: &OpenDatabase) -> Option<&Arc<RwLock<Self>>>;
+}
+
+// settings given in create database command
+#[derive(Clone, Copy)]
+pub struct DatabaseSettings {
+    pub hash_divider: u64,
+    pub byte_keys: bool,
+}
+
+impl DatabaseSettings {
+    // hash divider: u64, flags: u8 (bit 0 - byte keys)
+    fn load(folder: &Path) -> Result<Option<DatabaseSettings>, Error> {
+        let path = folder.join(SETTINGS_FILE_NAME);
+        if !path.try_exists()? {
+            return Ok(None);
+        }
+        let data = fs::read(path)?;
+        if data.len() != 9 {
+            return Err(Error::new(ErrorKind::InvalidData, "incorrect database settings file"));
+        }
+        Ok(Some(DatabaseSettings{hash_divider: u64::from_le_bytes(data[0..8].try_into().unwrap()),
+                                 byte_keys: data[8] & 1 != 0}))
+    }
+
+    fn save(&self, folder: &Path) -> Result<(), Error> {
+        let mut data = self.hash_divider.to_le_bytes().to_vec();
+        data.push(if self.byte_keys {1} else {0});
+        fs::write(folder.join(SETTINGS_FILE_NAME), data)
+    }
+}
+
+impl StoredDatabase for Database {
+    const BYTE_KEYS: bool = false;
+    const WRONG_KEY_TYPE: &'static str = "database uses byte keys";
+
+    fn open(path: PathBuf, hash_divider: u64) -> Result<Self, Error> {
+        Database::new(path, hash_divider)
+    }
+
+    fn wrap(db: Arc<RwLock<Self>>) -> OpenDatabase {
+        OpenDatabase::Integer(db)
+    }
+
+    fn unwrap(db: &OpenDatabase) -> Option<&Arc<RwLock<Self>>> {
+        match db {
+            OpenDatabase::Integer(db) => Some(db),
+            OpenDatabase::ByteKey(_) => None
+        }
+    }
+}
+
+impl StoredDatabase for ByteKeyDatabase {
+    const BYTE_KEYS: bool = true;
+    const WRONG_KEY_TYPE: &'static str = "database does not use byte keys";
+
+    fn open(path: PathBuf, _hash_divider: u64) -> Result<Self, Error> {
+        ByteKeyDatabase::new(path)
+    }
+
+    fn wrap(db: Arc<RwLock<Self>>) -> OpenDatabase {
+        OpenDatabase::ByteKey(db)
+    }
+
+    fn unwrap(db: &OpenDatabase) -> Option<&Arc<RwLock<Self>>> {
+        match db {
+            OpenDatabase::Integer(_) => None,
+            OpenDatabase::ByteKey(db) => Some(db)
+        }
+    }
+}
+
+impl OpenDatabase {
+    fn write(&self) -> OpenDatabaseLock<'_> {
+        match self {
+            OpenDatabase::Integer(db) => OpenDatabaseLock::Integer(db.write().unwrap()),
+            OpenDatabase::ByteKey(db) => OpenDatabaseLock::ByteKey(db.write().unwrap())
+        }
+    }
+
+    fn is_same(&self, other: &OpenDatabase) -> bool {
+        match (self, other) {
+            (OpenDatabase::Integer(db1), OpenDatabase::Integer(db2)) => Arc::ptr_eq(db1, db2),
+            (OpenDatabase::ByteKey(db1), OpenDatabase::ByteKey(db2)) => Arc::ptr_eq(db1, db2),
+            _ => false
+        }
+    }
+
+    // address of database, used as lock order
+    fn address(&self) -> usize {
+        match self {
+            OpenDatabase::Integer(db) => Arc::as_ptr(db) as usize,
+            OpenDatabase::ByteKey(db) => Arc::as_ptr(db) as usize
+        }
+    }
+}
+
+impl OpenDatabaseLock<'_> {
+    fn get_settings(&self, default_hash_divider: u64) -> DatabaseSettings {
+        match self {
+            OpenDatabaseLock::Integer(db) => DatabaseSettings{hash_divider: db.get_hash_divider(), byte_keys: false},
+            OpenDatabaseLock::ByteKey(_) => DatabaseSettings{hash_divider: default_hash_divider, byte_keys: true}
+        }
+    }
+
+    // database folder was renamed
+    fn set_base_folder(&mut self, path: PathBuf) {
+        match self {
+            OpenDatabaseLock::Integer(db) => db.set_base_folder(path),
+            OpenDatabaseLock::ByteKey(db) => db.set_base_folder(path)
+        }
+    }
+
+    fn set_version(&mut self, version: u32) {
+        match self {
+            OpenDatabaseLock::Integer(db) => db.set_version(version),
+            OpenDatabaseLock::ByteKey(db) => db.set_version(version)
+        }
+    }
 }
 
 impl Databases {
-    pub fn new(base_folder: String, hash_divider: usize) -> Result<Databases, Error> {
+    pub fn new(base_folder: String, hash_divider: u64, byte_key_databases: HashSet<String>, implicit_creation: bool)
+        -> Result<Databases, Error> {
         let base_path = PathBuf::from(base_folder);
+        recover_swap(&base_path)?;
         let contents = fs::read_dir(&base_path)?;
-        let mut result = HashMap::new();
+        let mut databases = Databases{data: RwLock::new(HashMap::new()), base_path, hash_divider, byte_key_databases,
+                                      implicit_creation};
         for entry_result in contents {
             let entry = entry_result?;
             if entry.file_type()?.is_dir() {
-                result.insert(entry.file_name().into_string().unwrap(),
-                              RwLock::new(Database::new(entry.path(), hash_divider)?));
+                let name = entry.file_name().into_string().unwrap();
+                if name != TRASH_FOLDER_NAME && name != SWAP_FOLDER_NAME {
+                    let db = databases.open_database(&name, entry.path())?;
+                    databases.data.get_mut().unwrap().insert(name, db);
+                }
+            }
+        }
+        empty_trash(databases.base_path.join(TRASH_FOLDER_NAME));
+        Ok(databases)
+    }
+
+    // hash divider 0 - server default
+    pub fn create_database(&self, database: String, settings: DatabaseSettings) -> Result<(), Error> {
+        let mut map = self.data.write().unwrap();
+        self.validate_target(&map, &database, settings.byte_keys)?;
+        let settings = DatabaseSettings{
+            hash_divider: if settings.hash_divider == 0 {self.hash_divider} else {settings.hash_divider},
+            byte_keys: settings.byte_keys
+        };
+        let path = self.base_path.join(&database);
+        fs::create_dir(&path)?;
+        settings.save(&path)?;
+        let db = self.open_database(&database, path)?;
+        map.insert(database, db);
+        Ok(())
+    }
+
+    // copies database files, hard links are not used because files are rewritten in place
+    pub fn clone_database(&self, source: String, target: String) -> Result<(), Error> {
+        let byte_keys = match self.get_database(&source) {
+            Some(db) => matches!(db, OpenDatabase::ByteKey(_)),
+            None => return Err(Error::new(ErrorKind::NotFound, "database does not exist"))
+        };
+        self.validate_target(&self.data.read().unwrap(), &target, byte_keys)?;
+        // files are copied to trash folder first, so partially copied database is never loaded
+        let copy_path = self.create_trash_path(&target)?;
+        let source_path = self.base_path.join(&source);
+        // source database is locked for reading while its files are copied
+        let result = if byte_keys {
+            self.read(&source, |db: &ByteKeyDatabase| -> Result<(u32, DatabaseSettings), Error> {
+                copy_folder(&source_path, &copy_path)?;
+                Ok((db.get_version(), DatabaseSettings{hash_divider: self.hash_divider, byte_keys: true}))
+            })?
+        } else {
+            self.read(&source, |db: &Database| -> Result<(u32, DatabaseSettings), Error> {
+                copy_folder(&source_path, &copy_path)?;
+                Ok((db.get_version(), DatabaseSettings{hash_divider: db.get_hash_divider(), byte_keys: false}))
+            })?
+        };
+        let (version, settings) = match result {
+            Some(result) => result?,
+            None => return Err(Error::new(ErrorKind::NotFound, "database does not exist"))
+        };
+        settings.save(&copy_path)?;
+        // copy is loaded before map lock is taken
+        let db = self.open_database(&target, copy_path.clone())?;
+        let mut map = self.data.write().unwrap();
+        // target database could be created while files were copied
+        if let Err(e) = self.validate_target(&map, &target, settings.byte_keys) {
+            fs::remove_dir_all(&copy_path)?;
+            return Err(e);
+        }
+        let path = self.base_path.join(&target);
+        fs::rename(&copy_path, &path)?;
+        {
+            let mut lock = db.write();
+            lock.set_base_folder(path);
+            lock.set_version(version);
+        }
+        map.insert(target, db);
+        Ok(())
+    }
+
+    // database is locked first, so commands that found it by old name finish before the rename
+    pub fn rename_database(&self, source: String, target: String) -> Result<(), Error> {
+        let db = self.get_database(&source)
+            .ok_or(Error::new(ErrorKind::NotFound, "database does not exist"))?;
+        let mut lock = db.write();
+        let mut map = self.data.write().unwrap();
+        if !is_current(&map, &source, &db) {
+            drop(map);
+            drop(lock);
+            return self.rename_database(source, target);
+        }
+        let settings = lock.get_settings(self.hash_divider);
+        self.validate_target(&map, &target, settings.byte_keys)?;
+        // settings file keeps keys type and hash divider when database is not declared in configuration
+        settings.save(&self.base_path.join(&source))?;
+        let path = self.base_path.join(&target);
+        fs::rename(self.base_path.join(&source), &path)?;
+        lock.set_base_folder(path);
+        map.remove(&source);
+        map.insert(target, db.clone());
+        Ok(())
+    }
+
+    // on disk swap is done with three renames, completed renames are undone when a later rename fails,
+    // in memory swap is atomic
+    pub fn swap_databases(&self, database1: String, database2: String) -> Result<(), Error> {
+        let not_found = ||Error::new(ErrorKind::NotFound, "database does not exist");
+        let db1 = self.get_database(&database1).ok_or_else(not_found)?;
+        let db2 = self.get_database(&database2).ok_or_else(not_found)?;
+        if database1 == database2 {
+            return Ok(());
+        }
+        // databases are locked in address order
+        let (mut lock1, mut lock2) = if db1.address() < db2.address() {
+            let lock1 = db1.write();
+            (lock1, db2.write())
+        } else {
+            let lock2 = db2.write();
+            (db1.write(), lock2)
+        };
+        let mut map = self.data.write().unwrap();
+        if !is_current(&map, &database1, &db1) || !is_current(&map, &database2, &db2) {
+            drop(map);
+            drop(lock1);
+            drop(lock2);
+            return self.swap_databases(database1, database2);
+        }
+        let settings1 = lock1.get_settings(self.hash_divider);
+        let settings2 = lock2.get_settings(self.hash_divider);
+        self.validate_key_type(&database1, settings2.byte_keys)?;
+        self.validate_key_type(&database2, settings1.byte_keys)?;
+        let path1 = self.base_path.join(&database1);
+        let path2 = self.base_path.join(&database2);
+        settings1.save(&path1)?;
+        settings2.save(&path2)?;
+        let swap_path = self.base_path.join(SWAP_FOLDER_NAME).join(&database2);
+        fs::create_dir_all(&swap_path)?;
+        let temp_path = swap_path.join(&database1);
+        fs::rename(&path1, &temp_path)?;
+        if let Err(e) = fs::rename(&path2, &path1) {
+            fs::rename(&temp_path, &path1)?;
+            return Err(e);
+        }
+        if let Err(e) = fs::rename(&temp_path, &path2) {
+            fs::rename(&path1, &path2)?;
+            fs::rename(&temp_path, &path1)?;
+            return Err(e);
+        }
+        // swap is complete, empty folder is removed at startup when this fails
+        let _ = fs::remove_dir(swap_path);
+        lock1.set_base_folder(path2);
+        lock2.set_base_folder(path1);
+        map.insert(database1, db2.clone());
+        map.insert(database2, db1.clone());
+        Ok(())
+    }
+
+    // database folder is renamed to trash folder first, so drop is atomic, files are deleted in background
+    pub fn drop_database(&self, database: String) -> Result<(), Error> {
+        let db = self.get_database(&database)
+            .ok_or(Error::new(ErrorKind::NotFound, "database does not exist"))?;
+        let mut lock = db.write();
+        let mut map = self.data.write().unwrap();
+        if !is_current(&map, &database, &db) {
+            drop(map);
+            drop(lock);
+            return self.drop_database(database);
+        }
+        let trash_path = self.create_trash_path(&database)?;
+        fs::rename(self.base_path.join(&database), &trash_path)?;
+        // commands that still hold dropped database never write to the new database with the same name
+        lock.set_base_folder(trash_path.clone());
+        map.remove(&database);
+        thread::spawn(move || {
+            if let Err(e) = fs::remove_dir_all(&trash_path) {
+                println!("Dropped database removal error: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    // None when database does not exist yet
+    pub fn get_database_version(&self, database: &str) -> Option<u32> {
+        match self.get_database(database)? {
+            OpenDatabase::Integer(_) => self.read(database, |db: &Database|db.get_version()).ok().flatten(),
+            OpenDatabase::ByteKey(_) => self.read(database, |db: &ByteKeyDatabase|db.get_version()).ok().flatten()
+        }
+    }
+
+    pub fn get_statistics(&self, database: String) -> Result<DatabaseStatistics, Error> {
+        if self.is_byte_key_database(&database) {
+            return Err(Error::new(ErrorKind::InvalidInput, "database uses byte keys"));
+        }
+        Ok(self.read(&database, |db: &Database|db.get_statistics())?
+            .unwrap_or(DatabaseStatistics{version: 1, ..DatabaseStatistics::default()}))
+    }
+
+    pub fn get(&self, database: String, key1: u64, key2: u64, options: &ScanOptions)
+        -> Result<(u32, Vec<(KeyValue, Modification)>), Error> {
+        Ok(self.read(&database, |db: &Database|{
+            let value = db.get(key1, key2, options);
+            let result = value.iter()
+                .map(|v|(KeyValue{key: v.key, version: v.version, value: v.value.clone()}, v.modified))
+                .collect();
+            (db.get_version(), result)
+        })?.unwrap_or((1, Vec::new())))
+    }
+
+    // returned values are empty when with_value is false
+    pub fn get_metadata(&self, database: String, key1: u64, key2: u64, with_value: bool)
+        -> Result<(u32, Vec<(KeyValue, Metadata)>), Error> {
+        Ok(self.read(&database, |db: &Database|{
+            let result = db.get(key1, key2, &ScanOptions::default()).iter()
+                .map(|v|(KeyValue{key: v.key, version: v.version,
+                                  value: if with_value {v.value.clone()} else {Vec::new()}},
+                         v.metadata.clone()))
+                .collect();
+            (db.get_version(), result)
+        })?.unwrap_or((1, Vec::new())))
+    }
+
+    pub fn find_by_attribute(&self, database: String, key1: u64, key2: u64, name: &str, value: &[u8])
+        -> Result<(u32, Vec<(u64, u32)>), Error> {
+        Ok(self.read(&database, |db: &Database|{
+            let result = db.find_by_attribute(key1, key2, name, value).iter()
+                .map(|v|(v.key, v.version))
+                .collect();
+            (db.get_version(), result)
+        })?.unwrap_or((1, Vec::new())))
+    }
+
+    pub fn get_modified_since(&self, database: String, key1: u64, key2: u64, since: u64)
+        -> Result<(u32, Vec<(KeyValue, Modification)>), Error> {
+        Ok(self.read(&database, |db: &Database|{
+            let value = db.get_modified_since(key1, key2, since);
+            let result = value.iter()
+                .map(|v|(KeyValue{key: v.key, version: v.version, value: v.value.clone()}, v.modified))
+                .collect();
+            (db.get_version(), result)
+        })?.unwrap_or((1, Vec::new())))
+    }
+
+    pub fn get_key_versions(&self, database: String, key1: u64, key2: u64, options: &ScanOptions)
+        -> Result<(u32, Vec<KeyVersion>), Error> {
+        Ok(self.read(&database, |db: &Database|{
+            let result = db.get(key1, key2, options).iter()
+                .map(|v|(v.key, v.version, v.value.len(), v.modified))
+                .collect();
+            (db.get_version(), result)
+        })?.unwrap_or((1, Vec::new())))
+    }
+
+    pub fn get_range_statistics(&self, database: String, key1: u64, key2: u64) -> Result<(u32, RangeStatistics), Error> {
+        Ok(self.read(&database, |db: &Database|(db.get_version(), db.get_range_statistics(key1, key2)))?
+            .unwrap_or((1, RangeStatistics::default())))
+    }
+
+    pub fn get_last(&self, database: String, key1: u64, key2: u64) -> Result<(u32, Option<KeyValue>), Error> {
+        Ok(self.read(&database, |db: &Database|{
+            let value = db.get_last(key1, key2);
+            let result = value
+                .map(|v|KeyValue{key: v.key, version: v.version, value: v.value.clone()});
+            (db.get_version(), result)
+        })?.unwrap_or((1, None)))
+    }
+
+    pub fn get_keys(&self, database: String, keys: Vec<u64>) -> Result<(u32, Vec<Option<KeyValue>>), Error> {
+        let count = keys.len();
+        Ok(self.read(&database, |db: &Database|{
+            let result = keys.into_iter()
+                .map(|key|db.get_key(key)
+                    .map(|v|KeyValue{key: v.key, version: v.version, value: v.value.clone()}))
+                .collect();
+            (db.get_version(), result)
+        })?.unwrap_or_else(||(1, vec![None; count])))
+    }
+
+    pub fn get_changed_keys(&self, database: String, versions: Vec<(u64, u32)>)
+        -> Result<(u32, Vec<ChangedKey>), Error> {
+        Ok(self.read(&database, |db: &Database|{
+            let result = db.get_changed_keys(versions.clone()).into_iter()
+                .map(|(key, value)|(key, value
+                    .map(|v|KeyValue{key: v.key, version: v.version, value: v.value.clone()})))
+                .collect();
+            (db.get_version(), result)
+        })?.unwrap_or_else(||(1, versions.into_iter()
+            .filter(|(_, version)|*version != 0)
+            .map(|(key, _)|(key, None))
+            .collect())))
+    }
+
+    pub fn get_changed_range(&self, database: String, key1: u64, key2: u64, versions: HashMap<u64, u32>)
+        -> Result<(u32, Vec<ChangedKey>), Error> {
+        Ok(self.read(&database, |db: &Database|{
+            let result = db.get_changed_range(key1, key2, &versions).into_iter()
+                .map(|(key, value)|(key, value
+                    .map(|v|KeyValue{key: v.key, version: v.version, value: v.value.clone()})))
+                .collect();
+            (db.get_version(), result)
+        })?.unwrap_or_else(||{
+            let mut result: Vec<ChangedKey> = versions.iter()
+                .filter(|(key, version)|**version != 0 && **key >= key1 && **key <= key2)
+                .map(|(key, _)|(*key, None))
+                .collect();
+            result.sort_by_key(|(key, _)|*key);
+            (1, result)
+        }))
+    }
+
+    pub fn get_first(&self, database: String, key1: u64, key2: u64) -> Result<(u32, Option<KeyValue>), Error> {
+        Ok(self.read(&database, |db: &Database|{
+            let value = db.get_first(key1, key2);
+            let result = value
+                .map(|v|KeyValue{key: v.key, version: v.version, value: v.value.clone()});
+            (db.get_version(), result)
+        })?.unwrap_or((1, None)))
+    }
+
+    pub fn navigate(&self, database: String, key: u64, navigation: Navigation) -> Result<(u32, Option<KeyValue>), Error> {
+        Ok(self.read(&database, |db: &Database|{
+            let value = db.navigate(key, navigation);
+            let result = value
+                .map(|v|KeyValue{key: v.key, version: v.version, value: v.value.clone()});
+            (db.get_version(), result)
+        })?.unwrap_or((1, None)))
+    }
+
+    // returns file version, full value length and value[offset..offset+length]
+    pub fn get_value_part(&self, database: String, key: u64, offset: usize, length: usize)
+        -> Result<(u32, Option<ValuePart>), Error> {
+        Ok(self.read(&database, |db: &Database|{
+            let result = db.get_key(key)
+                .map(|v|{
+                    let start = offset.min(v.value.len());
+                    let end = start.saturating_add(length).min(v.value.len());
+                    (v.version, v.value.len(), v.value[start..end].to_vec())
+                });
+            (db.get_version(), result)
+        })?.unwrap_or((1, None)))
+    }
+
+    pub fn get_file_version(&self, database: String, key: u64) -> Result<(u32, Option<u32>), Error> {
+        Ok(self.read(&database, |db: &Database|(db.get_version(), db.get_file_version(key)))?
+            .unwrap_or((1, None)))
+    }
+
+    pub fn set(&self, database: String, expected_version: u32, data: Vec<KeyValue>, user_id: u32)
+        -> Result<(), Error> {
+        self.modify(database, |db: &mut Database|db.set(expected_version, data, user_id))
+    }
+
+    pub fn set_with_ttl(&self, database: String, expected_version: u32, data: Vec<KeyValue>, user_id: u32,
+                        ttl: Duration) -> Result<(), Error> {
+        self.modify(database, |db: &mut Database|db.set_with_ttl(expected_version, data, user_id, Some(ttl)))
+    }
+
+    pub fn set_with_metadata(&self, database: String, expected_version: u32, data: Vec<(KeyValue, Metadata)>,
+                             user_id: u32) -> Result<(), Error> {
+        self.modify(database, |db: &mut Database|db.set_with_metadata(expected_version, data, user_id))
+    }
+
+    pub fn append(&self, database: String, key1: u64, key2: u64, value: Vec<u8>, user_id: u32)
+        -> Result<(u64, u32), Error> {
+        self.modify(database, |db: &mut Database|{
+            let key = db.append(key1, key2, value, user_id)?;
+            Ok((key, db.get_version()))
+        })
+    }
+
+    pub fn write_value(&self, database: String, expected_version: u32, key: u64, offset: Option<usize>,
+                       bytes: &[u8], user_id: u32) -> Result<(u32, u32), Error> {
+        self.modify(database, |db: &mut Database|{
+            db.write_value(expected_version, key, offset, bytes, user_id)?;
+            Ok((db.get_version(), db.get_file_version(key).unwrap_or(0)))
+        })
+    }
+
+    pub fn patch(&self, database: String, expected_version: u32, key: u64, base_version: u32,
+                 patch: &[u8], user_id: u32) -> Result<(u32, u32), Error> {
+        self.modify(database, |db: &mut Database|{
+            db.patch(expected_version, key, base_version, patch, user_id)?;
+            Ok((db.get_version(), db.get_file_version(key).unwrap_or(0)))
+        })
+    }
+
+    pub fn increment(&self, database: String, key: u64, delta: i64, user_id: u32)
+        -> Result<(u32, u32, i64), Error> {
+        self.modify(database, |db: &mut Database|{
+            let value = db.increment(key, delta, user_id)?;
+            Ok((db.get_version(), db.get_file_version(key).unwrap_or(0), value))
+        })
+    }
+
+    // all groups are applied or none, missing databases are created only when all versions match
+    // returns new database versions in groups order
+    pub fn transaction(&self, groups: Vec<TransactionGroup>, user_id: u32) -> Result<Vec<u32>, Error> {
+        let mut groups: Vec<(usize, TransactionGroup)> = groups.into_iter().enumerate().collect();
+        groups.sort_by(|(_, a), (_, b)|a.database.cmp(&b.database));
+        if groups.windows(2).any(|w|w[0].1.database == w[1].1.database) {
+            return Err(Error::new(ErrorKind::InvalidInput, "duplicate database in transaction"));
+        }
+        loop {
+            let mut existing = Vec::new();
+            let mut missing = Vec::new();
+            for (i, (_, group)) in groups.iter().enumerate() {
+                match self.get_typed::<Database>(&group.database)? {
+                    Some(db) => existing.push((i, db)),
+                    None => {
+                        if self.byte_key_databases.contains(&group.database) {
+                            return Err(Error::new(ErrorKind::InvalidInput, Database::WRONG_KEY_TYPE));
+                        }
+                        self.validate_implicit_creation(&group.database)?;
+                        missing.push(i);
+                    }
+                }
+            }
+            // databases are locked in address order to avoid deadlocks, names can be swapped between lookups
+            existing.sort_by_key(|(_, db)|Arc::as_ptr(db));
+            let mut created = Vec::new();
+            let mut locks: Vec<(usize, RwLockWriteGuard<Database>)> = existing.iter()
+                .map(|(i, db)|(*i, db.write().unwrap()))
+                .collect();
+            let mut map = self.data.write().unwrap();
+            // some database was created, dropped or renamed while locks were taken
+            if !existing.iter().all(|(i, db)|is_current(&map, &groups[*i].1.database, &Database::wrap(db.clone()))) ||
+                missing.iter().any(|i|map.contains_key(&groups[*i].1.database)) {
+                continue;
+            }
+            // new database has version 1
+            if locks.iter().any(|(i, lock)|lock.get_version() != groups[*i].1.expected_version) ||
+                missing.iter().any(|i|groups[*i].1.expected_version != 1) {
+                return Err(Error::new(ErrorKind::InvalidData, "version mismatch"));
+            }
+            for i in missing {
+                let path = self.base_path.join(&groups[i].1.database);
+                match fs::create_dir(&path).and_then(|_|Database::new(path.clone(), self.hash_divider)) {
+                    Ok(db) => created.push((i, Arc::new(RwLock::new(db)), path)),
+                    Err(e) => {
+                        for (_, _, path) in &created {
+                            fs::remove_dir_all(path)?;
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            // new databases are locked before they are added to the map
+            for (i, db, _) in &created {
+                locks.push((*i, db.write().unwrap()));
+                map.insert(groups[*i].1.database.clone(), Database::wrap(db.clone()));
+            }
+            drop(map);
+            let mut changes = Vec::new();
+            let mut error = None;
+            for (i, lock) in locks.iter_mut() {
+                let group = &mut groups[*i].1;
+                match lock.set_revertible(group.expected_version, std::mem::take(&mut group.data), user_id) {
+                    Ok(c) => changes.push((*i, c)),
+                    Err(e) => {
+                        error = Some(e);
+                        break;
+                    }
+                }
+            }
+            if let Some(e) = error {
+                for (i, c) in changes.into_iter().rev() {
+                    locks.iter_mut().find(|(j, _)|*j == i).unwrap().1.revert(c)?;
+                }
+                let mut map = self.data.write().unwrap();
+                for (i, _, path) in &created {
+                    map.remove(&groups[*i].1.database);
+                    fs::remove_dir_all(path)?;
+                }
+                return Err(e);
+            }
+            let mut versions = vec![0; groups.len()];
+            for (i, lock) in &locks {
+                versions[groups[*i].0] = lock.get_version();
+            }
+            return Ok(versions);
+        }
+    }
+
+    // copies or moves keys in range [key1, key2] with their metadata and expiration time to target database,
+    // target change is reverted when keys removal from source database fails
+    // returns source database version, target database version and number of keys
+    pub fn copy_range(&self, source: String, target: String, key1: u64, key2: u64, move_keys: bool, user_id: u32)
+        -> Result<(u32, u32, usize), Error> {
+        if source == target {
+            return Err(Error::new(ErrorKind::InvalidInput, "source and target databases are the same"));
+        }
+        loop {
+            let source_db = self.get_typed::<Database>(&source)?
+                .ok_or(Error::new(ErrorKind::NotFound, "database does not exist"))?;
+            // creates target database when it does not exist
+            let target_db = self.get_or_create::<Database>(&target)?;
+            // both databases are locked in address order until the end of the operation,
+            // names can be swapped between lookups, addresses of open databases can not
+            let (mut source_lock, mut target_lock) = if Arc::as_ptr(&source_db) < Arc::as_ptr(&target_db) {
+                let source_lock = source_db.write().unwrap();
+                (source_lock, target_db.write().unwrap())
+            } else {
+                let target_lock = target_db.write().unwrap();
+                (source_db.write().unwrap(), target_lock)
+            };
+            if !self.is_current(&source, &source_db) || !self.is_current(&target, &target_db) {
+                continue;
+            }
+            let data: Vec<(KeyValue, Metadata, Option<u64>)> = source_lock.get(key1, key2, &ScanOptions::default())
+                .iter()
+                .map(|v|(KeyValue{key: v.key, version: 0, value: v.value.clone()}, v.metadata.clone(), v.expires_at))
+                .collect();
+            let count = data.len();
+            if count != 0 {
+                let keys: Vec<u64> = data.iter().map(|(kv, _, _)|kv.key).collect();
+                let version = target_lock.get_version();
+                let changes = target_lock.set_copies(version, data, user_id)?;
+                let removed = if move_keys {source_lock.remove_keys(keys)} else {Ok(())};
+                if let Err(e) = removed {
+                    target_lock.revert(changes)?;
+                    return Err(e);
+                }
+            }
+            return Ok((source_lock.get_version(), target_lock.get_version(), count));
+        }
+    }
+
+    pub fn pop(&self, database: String, key1: u64, key2: u64, last: bool, claim_timeout: Option<Duration>)
+        -> Result<(u32, Option<KeyValue>), Error> {
+        self.write(&database, |db: &mut Database|{
+            let result = db.pop(key1, key2, last, claim_timeout)?;
+            Ok((db.get_version(), result))
+        })?.unwrap_or(Ok((1, None)))
+    }
+
+    pub fn ack(&self, database: String, key: u64, release: bool) -> Result<u32, Error> {
+        self.write(&database, |db: &mut Database|{
+            if release {
+                db.nack(key)?;
+            } else {
+                db.ack(key)?;
+            }
+            Ok(db.get_version())
+        })?.unwrap_or(Err(Error::new(ErrorKind::InvalidInput, "key is not claimed")))
+    }
+
+    pub fn get_byte_keys(&self, database: String, keys: Vec<Vec<u8>>) -> Result<(u32, Vec<Option<ByteKeyValue>>), Error> {
+        let count = keys.len();
+        Ok(self.read(&database, |db: &ByteKeyDatabase|(db.get_version(), db.get_keys(keys)))?
+            .unwrap_or_else(||(1, vec![None; count])))
+    }
+
+    pub fn get_byte_key_prefix(&self, database: String, prefix: &[u8], options: &ScanOptions)
+        -> Result<(u32, Vec<ByteKeyValue>), Error> {
+        Ok(self.read(&database, |db: &ByteKeyDatabase|(db.get_version(), db.get_prefix(prefix, options)))?
+            .unwrap_or((1, Vec::new())))
+    }
+
+    pub fn get_byte_key_range(&self, database: String, key1: &[u8], key2: &[u8], options: &ScanOptions)
+        -> Result<(u32, Vec<ByteKeyValue>), Error> {
+        Ok(self.read(&database, |db: &ByteKeyDatabase|(db.get_version(), db.get_range(key1, key2, options)))?
+            .unwrap_or((1, Vec::new())))
+    }
+
+    pub fn set_byte_keys(&self, database: String, expected_version: u32, data: Vec<ByteKeyValue>,
+                         user_id: u32) -> Result<(), Error> {
+        self.modify(database, |db: &mut ByteKeyDatabase|db.set(expected_version, data, user_id))
+    }
+
+    // error in one database does not stop removal in other ones
+    pub fn remove_expired(&self) {
+        // byte key databases have no expiration
+        let names: Vec<String> = self.data.read().unwrap().iter()
+            .filter(|(_, db)|matches!(db, OpenDatabase::Integer(_)))
+            .map(|(name, _)|name.clone())
+            .collect();
+        for name in names {
+            if let Err(e) = self.remove_expired_keys(&name) {
+                println!("Expired keys removal error in database {}: {}", name, e);
             }
         }
-        Ok(Databases{data: result, base_path, hash_divider})
     }
 
-    pub fn get(&self, database: String, key1: usize, key2: usize) -> (u32, Vec<KeyValue>) {
-        match self.data.get(&database) {
-            Some(data) => {
-                let lock = data.read().unwrap();
-                let value = lock.get(key1, key2);
-                let result = value.iter()
-                    .map(|v|KeyValue{key: v.key, version: v.version, value: v.value.clone()})
-                    .collect();
-                (lock.get_version(), result)
-            },
-            None => (1, Vec::new())
+    // expired keys are found under read lock, write lock is taken only when some key has expired
+    fn remove_expired_keys(&self, database: &str) -> Result<usize, Error> {
+        let keys = self.read(database, |db: &Database|db.get_expired_keys())?.unwrap_or_default();
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        self.write(database, |db: &mut Database|db.remove_expired(keys))?.unwrap_or(Ok(0))
+    }
+
+    // reads settings file and loads database from path folder
+    fn open_database(&self, database: &str, path: PathBuf) -> Result<OpenDatabase, Error> {
+        let settings = DatabaseSettings::load(&path)?;
+        if settings.map(|s|s.byte_keys).unwrap_or(self.byte_key_databases.contains(database)) {
+            Ok(OpenDatabase::ByteKey(Arc::new(RwLock::new(ByteKeyDatabase::new(path)?))))
+        } else {
+            let hash_divider = settings.map(|s|s.hash_divider).unwrap_or(self.hash_divider);
+            Ok(OpenDatabase::Integer(Arc::new(RwLock::new(Database::new(path, hash_divider)?))))
         }
     }
 
-    pub fn get_last(&self, database: String, key1: usize, key2: usize) -> (u32, Option<KeyValue>) {
-        match self.data.get(&database) {
-            Some(data) => {
-                let lock = data.read().unwrap();
-                let value = lock.get_last(key1, key2);
-                let result = value
-                    .map(|v|KeyValue{key: v.key, version: v.version, value: v.value.clone()});
-                (lock.get_version(), result)
-            },
-            None => (1, None)
+    // map lock is released before database lock is taken
+    fn get_database(&self, database: &str) -> Option<OpenDatabase> {
+        self.data.read().unwrap().get(database).cloned()
+    }
+
+    // checked with database lock held: database was not dropped, renamed or swapped after it was found
+    fn is_current<D: StoredDatabase>(&self, database: &str, db: &Arc<RwLock<D>>) -> bool {
+        is_current(&self.data.read().unwrap(), database, &D::wrap(db.clone()))
+    }
+
+    // None when database does not exist, error when it uses another keys type
+    fn get_typed<D: StoredDatabase>(&self, database: &str) -> Result<Option<Arc<RwLock<D>>>, Error> {
+        match self.get_database(database) {
+            Some(db) => D::unwrap(&db).cloned().map(Some)
+                .ok_or(Error::new(ErrorKind::InvalidInput, D::WRONG_KEY_TYPE)),
+            None => Ok(None)
         }
     }
-    
-    pub fn get_file_version(&self, database: String, key: usize) -> (u32, Option<u32>) {
-        match self.data.get(&database) {
-            Some(data) => {
-                let lock = data.read().unwrap();
-                (lock.get_version(), lock.get_file_version(key))
-            },
-            None => (1, None)
+
+    // None when database does not exist
+    fn read<D: StoredDatabase, T>(&self, database: &str, f: impl FnOnce(&D) -> T) -> Result<Option<T>, Error> {
+        let mut f = Some(f);
+        loop {
+            let db = match self.get_typed::<D>(database)? {
+                Some(db) => db,
+                None => return Ok(None)
+            };
+            let lock = db.read().unwrap();
+            if self.is_current(database, &db) {
+                return Ok(Some(f.take().unwrap()(&lock)));
+            }
         }
     }
-    
-    pub fn set(&mut self, database: String, expected_version: u32, data: Vec<KeyValue>)
+
+    // same as read, but database is locked for writing
+    fn write<D: StoredDatabase, T>(&self, database: &str, f: impl FnOnce(&mut D) -> T) -> Result<Option<T>, Error> {
+        let mut f = Some(f);
+        loop {
+            let db = match self.get_typed::<D>(database)? {
+                Some(db) => db,
+                None => return Ok(None)
+            };
+            let mut lock = db.write().unwrap();
+            if self.is_current(database, &db) {
+                return Ok(Some(f.take().unwrap()(&mut lock)));
+            }
+        }
+    }
+
+    // missing database is created when implicit creation is enabled
+    fn get_or_create<D: StoredDatabase>(&self, database: &str) -> Result<Arc<RwLock<D>>, Error> {
+        self.modify(database.to_string(), |_: &mut D|Ok(()))?;
+        self.get_database(database).as_ref().and_then(D::unwrap).cloned()
+            .ok_or(Error::new(ErrorKind::NotFound, "database does not exist"))
+    }
+
+    fn modify<D: StoredDatabase, T>(&self, database: String, f: impl FnOnce(&mut D) -> Result<T, Error>)
+        -> Result<T, Error> {
+        let mut f = Some(f);
+        loop {
+            let db = match self.get_typed::<D>(&database)? {
+                Some(db) => db,
+                None => return self.create_and_modify(database, f.take().unwrap())
+            };
+            let mut lock = db.write().unwrap();
+            if self.is_current(&database, &db) {
+                return f.take().unwrap()(&mut lock);
+            }
+        }
+    }
+
+    // new database is locked before it is added to the map, so other commands wait for the first change,
+    // database is removed when the first change fails
+    fn create_and_modify<D: StoredDatabase, T>(&self, database: String, f: impl FnOnce(&mut D) -> Result<T, Error>)
+        -> Result<T, Error> {
+        if self.byte_key_databases.contains(&database) != D::BYTE_KEYS {
+            return Err(Error::new(ErrorKind::InvalidInput, D::WRONG_KEY_TYPE));
+        }
+        self.validate_implicit_creation(&database)?;
+        let mut map = self.data.write().unwrap();
+        if map.contains_key(&database) {
+            // created by another command
+            drop(map);
+            return self.modify(database, f);
+        }
+        let path = self.base_path.join(&database);
+        fs::create_dir(&path)?;
+        let db = Arc::new(RwLock::new(D::open(path.clone(), self.hash_divider)?));
+        let mut lock = db.write().unwrap();
+        map.insert(database.clone(), D::wrap(db.clone()));
+        drop(map);
+        let result = f(&mut lock);
+        if result.is_err() {
+            self.data.write().unwrap().remove(&database);
+            fs::remove_dir_all(path)?;
+        }
+        result
+    }
+
+    fn validate_target(&self, map: &HashMap<String, OpenDatabase>, database: &str, byte_keys: bool)
         -> Result<(), Error> {
-        match self.data.get(&database) {
-            Some(db) => {
-                let mut lock = db.write().unwrap();
-                lock.set(expected_version, data)
-            },
-            None => {
-                let path = self.base_path.join(&database);
-                fs::create_dir(&path)?;
-                let mut db = Database::new(path, self.hash_divider)?;
-                db.set(expected_version, data)?;
-                self.data.insert(database.clone(), RwLock::new(db));
-                Ok(())
-            }
-        }
-    }
-}
\ No newline at end of file
+        validate_database_name(database)?;
+        if map.contains_key(database) {
+            return Err(Error::new(ErrorKind::AlreadyExists, "database already exists"));
+        }
+        self.validate_key_type(database, byte_keys)
+    }
+
+    fn validate_key_type(&self, database: &str, byte_keys: bool) -> Result<(), Error> {
+        if self.byte_key_databases.contains(database) && !byte_keys {
+            return Err(Error::new(ErrorKind::InvalidInput, "database is declared with byte keys"));
+        }
+        Ok(())
+    }
+
+    fn create_trash_path(&self, database: &str) -> Result<PathBuf, Error> {
+        let trash = self.base_path.join(TRASH_FOLDER_NAME);
+        if !fs::exists(&trash)? {
+            fs::create_dir(&trash)?;
+        }
+        Ok(trash.join(format!("{}.{}", database, unix_time_ms())))
+    }
+
+    fn is_byte_key_database(&self, database: &str) -> bool {
+        self.byte_key_databases.contains(database) ||
+            matches!(self.get_database(database), Some(OpenDatabase::ByteKey(_)))
+    }
+
+    fn validate_implicit_creation(&self, database: &str) -> Result<(), Error> {
+        if !self.implicit_creation {
+            return Err(Error::new(ErrorKind::NotFound, "database does not exist"));
+        }
+        validate_database_name(database)
+    }
+}
+
+fn is_current(map: &HashMap<String, OpenDatabase>, database: &str, db: &OpenDatabase) -> bool {
+    map.get(database).is_some_and(|d|d.is_same(db))
+}
+
+// database name is used as folder name
+fn validate_database_name(database: &str) -> Result<(), Error> {
+    if database.is_empty() || database.starts_with('.') || database.contains(['/', '\\']) {
+        return Err(Error::new(ErrorKind::InvalidInput, "invalid database name"));
+    }
+    Ok(())
+}
+
+fn copy_folder(source: &Path, target: &Path) -> Result<(), Error> {
+    fs::create_dir(target)?;
+    for entry_result in fs::read_dir(source)? {
+        let entry = entry_result?;
+        if entry.file_type()?.is_dir() {
+            copy_folder(&entry.path(), &target.join(entry.file_name()))?;
+        } else {
+            fs::copy(entry.path(), target.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+// swap renames database1 folder to .swap/database2/database1 first, then database2 folder to database1 folder
+// and then temporary folder to database2 folder. swap interrupted after the second rename is completed,
+// otherwise database1 folder is restored
+fn recover_swap(base_path: &Path) -> Result<(), Error> {
+    let swap = base_path.join(SWAP_FOLDER_NAME);
+    if !swap.try_exists()? {
+        return Ok(());
+    }
+    for entry_result in fs::read_dir(&swap)? {
+        let entry = entry_result?;
+        for temp_result in fs::read_dir(entry.path())? {
+            let temp = temp_result?;
+            let path1 = base_path.join(temp.file_name());
+            if path1.try_exists()? {
+                fs::rename(temp.path(), base_path.join(entry.file_name()))?;
+            } else {
+                fs::rename(temp.path(), path1)?;
+            }
+        }
+    }
+    fs::remove_dir_all(swap)
+}
+
+// removes databases left in trash folder by previous run
+fn empty_trash(trash: PathBuf) {
+    let paths: Vec<PathBuf> = match fs::read_dir(trash) {
+        Ok(contents) => contents.flatten().map(|entry|entry.path()).collect(),
+        Err(_) => return
+    };
+    thread::spawn(move || {
+        for path in paths {
+            if let Err(e) = fs::remove_dir_all(path) {
+                println!("Dropped database removal error: {}", e);
+            }
+        }
+    });
+}
+
+pub fn start_expiration_sweeper(databases: Arc<Databases>) {
+    thread::spawn(move || loop {
+        thread::sleep(EXPIRATION_SWEEP_INTERVAL);
+        databases.remove_expired();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::io::Error;
+    use std::path::Path;
+    use std::thread;
+    use std::time::Duration;
+    use crate::byte_key_database::ByteKeyValue;
+    use crate::database::{KeyValue, ScanOptions};
+    use crate::databases::{DatabaseSettings, Databases, TransactionGroup};
+
+    fn kv(key: u64, value: u8) -> KeyValue {
+        KeyValue{key, version: 0, value: vec![value]}
+    }
+
+    fn value(databases: &Databases, database: &str, key: u64) -> Result<Option<Vec<u8>>, Error> {
+        let (_, values) = databases.get_keys(database.to_string(), vec![key])?;
+        Ok(values.into_iter().next().unwrap().map(|kv|kv.value))
+    }
+
+    fn group(database: &str, expected_version: u32, data: Vec<KeyValue>) -> TransactionGroup {
+        TransactionGroup{database: database.to_string(), expected_version, data}
+    }
+
+    #[test]
+    fn test_create_drop() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_databases_create";
+        fs::create_dir(path)?;
+        let databases = Databases::new(path.to_string(), 10000, HashSet::new(), true)?;
+        databases.set("a".to_string(), 1, vec![kv(1, 1)], 1)?;
+        assert_eq!(databases.get_database_version("a"), Some(2));
+        // failed first change removes created database
+        assert!(databases.set("b".to_string(), 5, vec![kv(1, 1)], 1).is_err());
+        assert_eq!(databases.get_database_version("b"), None);
+        assert!(!fs::exists(Path::new(path).join("b"))?);
+        assert!(databases.set(".b".to_string(), 1, vec![kv(1, 1)], 1).is_err());
+        // missing database reads as empty database with version 1
+        assert_eq!(databases.get("c".to_string(), 0, 10, &ScanOptions::default())?.0, 1);
+        let settings = DatabaseSettings{hash_divider: 0, byte_keys: false};
+        databases.create_database("c".to_string(), settings)?;
+        assert_eq!(databases.get_database_version("c"), Some(1));
+        assert!(databases.create_database("a".to_string(), settings).is_err());
+        assert!(databases.create_database(".trash".to_string(), settings).is_err());
+        databases.drop_database("a".to_string())?;
+        assert_eq!(databases.get_database_version("a"), None);
+        assert!(!fs::exists(Path::new(path).join("a"))?);
+        assert!(databases.drop_database("a".to_string()).is_err());
+        let databases = Databases::new(path.to_string(), 10000, HashSet::new(), false)?;
+        assert!(databases.set("d".to_string(), 1, vec![kv(1, 1)], 1).is_err());
+        assert_eq!(databases.get_database_version("a"), None);
+        assert_eq!(databases.get_database_version("c"), Some(1));
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_key_types() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_databases_key_types";
+        fs::create_dir(path)?;
+        let databases = Databases::new(path.to_string(), 10000, HashSet::from(["bytes".to_string()]), true)?;
+        databases.set("a".to_string(), 1, vec![kv(1, 1)], 1)?;
+        // reads and changes of database with another keys type fail
+        assert!(databases.set("bytes".to_string(), 1, vec![kv(1, 1)], 1).is_err());
+        databases.set_byte_keys("bytes".to_string(), 1,
+                                vec![ByteKeyValue{key: vec![1], version: 0, value: vec![2]}], 1)?;
+        assert!(databases.get("bytes".to_string(), 0, 10, &ScanOptions::default()).is_err());
+        assert!(databases.get_statistics("bytes".to_string()).is_err());
+        assert!(databases.transaction(vec![group("bytes", 2, vec![kv(1, 1)])], 1).is_err());
+        assert!(databases.get_byte_keys("a".to_string(), vec![vec![1]]).is_err());
+        assert_eq!(databases.get_byte_keys("bytes".to_string(), vec![vec![1]])?.1[0].as_ref().unwrap().value, vec![2]);
+        // byte key databases are skipped by expiration sweep
+        databases.remove_expired();
+        assert_eq!(databases.get_database_version("bytes"), Some(2));
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_get_keys() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_databases_get_keys";
+        fs::create_dir(path)?;
+        let databases = Databases::new(path.to_string(), 10000, HashSet::new(), true)?;
+        databases.set("a".to_string(), 1, vec![kv(17, 1), kv(5003, 2), kv(90210, 3)], 1)?;
+        databases.set("a".to_string(), 2, vec![kv(5003, 4)], 1)?;
+        let (version, values) = databases.get_keys("a".to_string(), vec![90210, 4, 5003])?;
+        assert_eq!(version, 3);
+        let values: Vec<Option<(u64, u32, Vec<u8>)>> = values.into_iter()
+            .map(|v|v.map(|kv|(kv.key, kv.version, kv.value)))
+            .collect();
+        assert_eq!(values, vec![Some((90210, 1, vec![3])), None, Some((5003, 2, vec![4]))]);
+        let (version, values) = databases.get_keys("b".to_string(), vec![1, 2])?;
+        assert_eq!(version, 1);
+        assert!(values.iter().all(|v|v.is_none()) && values.len() == 2);
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_get_key_versions() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_databases_key_versions";
+        fs::create_dir(path)?;
+        let databases = Databases::new(path.to_string(), 10000, HashSet::new(), true)?;
+        databases.set("a".to_string(), 1, vec![kv(1, 1), kv(2, 2), kv(3, 3)], 5)?;
+        databases.set("a".to_string(), 2, vec![KeyValue{key: 2, version: 0, value: vec![2; 10]}], 7)?;
+        let (version, versions) = databases.get_key_versions("a".to_string(), 0, 10, &ScanOptions::default())?;
+        assert_eq!(version, 3);
+        let versions: Vec<(u64, u32, usize, u32)> = versions.into_iter()
+            .map(|(key, version, length, modified)|(key, version, length, modified.user_id))
+            .collect();
+        assert_eq!(versions, vec![(1, 1, 1, 5), (2, 2, 10, 7), (3, 1, 1, 5)]);
+        // same pagination as range reads
+        let options = ScanOptions{descending: true, step: 2, limit: 1};
+        let (_, versions) = databases.get_key_versions("a".to_string(), 0, 10, &options)?;
+        assert_eq!(versions.iter().map(|(key, _, _, _)|*key).collect::<Vec<u64>>(), vec![3]);
+        assert_eq!(databases.get_key_versions("b".to_string(), 0, 10, &ScanOptions::default())?.1.len(), 0);
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_get_value_part() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_databases_value_part";
+        fs::create_dir(path)?;
+        let databases = Databases::new(path.to_string(), 10000, HashSet::new(), true)?;
+        databases.set("a".to_string(), 1, vec![KeyValue{key: 1, version: 0, value: vec![1, 2, 3, 4, 5]}], 1)?;
+        databases.set("a".to_string(), 2, vec![KeyValue{key: 1, version: 0, value: vec![1, 2, 3, 4, 5, 6]}], 1)?;
+        assert_eq!(databases.get_value_part("a".to_string(), 1, 1, 2)?, (3, Some((2, 6, vec![2, 3]))));
+        // length past the end of value
+        assert_eq!(databases.get_value_part("a".to_string(), 1, 4, usize::MAX)?, (3, Some((2, 6, vec![5, 6]))));
+        // offset past the end of value
+        assert_eq!(databases.get_value_part("a".to_string(), 1, 10, 2)?, (3, Some((2, 6, Vec::new()))));
+        assert_eq!(databases.get_value_part("a".to_string(), 2, 0, 2)?, (3, None));
+        assert_eq!(databases.get_value_part("b".to_string(), 1, 0, 2)?, (1, None));
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_copy_range() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_databases_copy_range";
+        fs::create_dir(path)?;
+        let databases = Databases::new(path.to_string(), 10000, HashSet::new(), true)?;
+        databases.set("a".to_string(), 1, vec![kv(1, 1), kv(2, 2), kv(3, 3), kv(10, 10)], 1)?;
+        assert!(databases.copy_range("a".to_string(), "a".to_string(), 1, 3, false, 1).is_err());
+        assert!(databases.copy_range("c".to_string(), "b".to_string(), 1, 3, false, 1).is_err());
+        assert_eq!(databases.copy_range("a".to_string(), "b".to_string(), 3, 1, false, 1)?.2, 0);
+        assert_eq!(databases.copy_range("a".to_string(), "b".to_string(), 2, 3, false, 1)?, (2, 2, 2));
+        assert_eq!(databases.copy_range("a".to_string(), "b".to_string(), 1, 2, true, 1)?, (3, 3, 2));
+        let keys = |database: &str| -> Result<Vec<u64>, Error> {
+            Ok(databases.get(database.to_string(), 0, 100, &ScanOptions::default())?.1.into_iter()
+                .map(|(kv, _)|kv.key).collect())
+        };
+        assert_eq!(keys("a")?, vec![3, 10]);
+        assert_eq!(keys("b")?, vec![1, 2, 3]);
+        assert_eq!(value(&databases, "b", 1)?, Some(vec![1]));
+        // expiration time is copied
+        databases.set_with_ttl("a".to_string(), 3, vec![kv(5, 5)], 1, Duration::from_millis(50))?;
+        assert_eq!(databases.copy_range("a".to_string(), "b".to_string(), 5, 5, false, 1)?, (4, 4, 1));
+        assert_eq!(value(&databases, "b", 5)?, Some(vec![5]));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(value(&databases, "b", 5)?, None);
+        databases.remove_expired();
+        assert_eq!(databases.get_database_version("a"), Some(5));
+        assert_eq!(databases.get_database_version("b"), Some(5));
+        // key 10 file can not be deleted, so target change is reverted
+        let file = Path::new(path).join("a").join("0").join("10");
+        fs::remove_file(&file)?;
+        fs::create_dir(&file)?;
+        assert!(databases.copy_range("a".to_string(), "b".to_string(), 10, 10, true, 1).is_err());
+        assert_eq!(databases.get_database_version("a"), Some(5));
+        assert_eq!(databases.get_database_version("b"), Some(5));
+        assert_eq!(value(&databases, "a", 10)?, Some(vec![10]));
+        assert_eq!(value(&databases, "b", 10)?, None);
+        assert!(!fs::exists(Path::new(path).join("b").join("0").join("10"))?);
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_transaction() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_databases_transaction";
+        fs::create_dir(path)?;
+        let databases = Databases::new(path.to_string(), 10000, HashSet::new(), true)?;
+        for name in ["a", "b", "c"] {
+            databases.set(name.to_string(), 1, vec![kv(20000, 1)], 1)?;
+        }
+        // version mismatch, missing database is not created
+        assert!(databases.transaction(vec![group("a", 1, vec![kv(1, 2)]), group("d", 1, vec![kv(1, 2)])], 1)
+            .is_err());
+        assert_eq!(databases.get_database_version("d"), None);
+        assert!(!fs::exists(Path::new(path).join("d"))?);
+        assert!(databases.transaction(vec![group("a", 2, vec![kv(1, 2)]), group("a", 2, vec![kv(2, 2)])], 1)
+            .is_err());
+        // key 1 folder is a file in database c, so its change fails and all groups are reverted
+        fs::write(Path::new(path).join("c").join("0"), [0])?;
+        assert!(databases.transaction(vec![group("a", 2, vec![kv(1, 2), kv(20000, 2)]),
+                                           group("b", 2, vec![kv(20000, 2)]),
+                                           group("c", 2, vec![kv(20001, 2), kv(1, 2)]),
+                                           group("d", 1, vec![kv(1, 2)])], 1).is_err());
+        for name in ["a", "b", "c"] {
+            assert_eq!(databases.get_database_version(name), Some(2));
+            assert_eq!(value(&databases, name, 1)?, None);
+            assert_eq!(value(&databases, name, 20000)?, Some(vec![1]));
+        }
+        assert_eq!(value(&databases, "c", 20001)?, None);
+        assert_eq!(databases.get_database_version("d"), None);
+        assert!(!fs::exists(Path::new(path).join("d"))?);
+        // versions are returned in groups order
+        let versions = databases.transaction(vec![group("d", 1, vec![kv(1, 3)]),
+                                                  group("b", 2, vec![kv(1, 3)]),
+                                                  group("a", 2, vec![kv(1, 3)])], 1)?;
+        assert_eq!(versions, vec![2, 3, 3]);
+        let databases = Databases::new(path.to_string(), 10000, HashSet::new(), true)?;
+        for name in ["a", "b", "d"] {
+            assert_eq!(value(&databases, name, 1)?, Some(vec![3]));
+        }
+        assert_eq!(value(&databases, "c", 20001)?, None);
+        fs::remove_dir_all(path)
+    }
+
+    #[test]
+    fn test_swap_rename_clone() -> Result<(), Error> {
+        let path = "/mnt/ramdisk/test_databases_swap";
+        fs::create_dir(path)?;
+        let base = Path::new(path);
+        let databases = Databases::new(path.to_string(), 10000, HashSet::new(), true)?;
+        databases.set("a".to_string(), 1, vec![kv(1, 1)], 1)?;
+        databases.set("b".to_string(), 1, vec![kv(1, 2)], 1)?;
+        databases.set("b".to_string(), 2, vec![kv(2, 2)], 1)?;
+        databases.swap_databases("a".to_string(), "b".to_string())?;
+        assert_eq!(databases.get_database_version("a"), Some(3));
+        assert_eq!(databases.get_database_version("b"), Some(2));
+        assert_eq!(value(&databases, "a", 1)?, Some(vec![2]));
+        assert_eq!(value(&databases, "b", 1)?, Some(vec![1]));
+        assert!(!fs::exists(base.join(".swap").join("b"))?);
+        // changes are saved to swapped folders
+        databases.set("a".to_string(), 3, vec![kv(3, 3)], 1)?;
+        assert!(fs::exists(base.join("a").join("0").join("3"))?);
+        databases.rename_database("a".to_string(), "c".to_string())?;
+        assert_eq!(databases.get_database_version("a"), None);
+        assert_eq!(databases.get_database_version("c"), Some(4));
+        assert!(databases.rename_database("c".to_string(), "b".to_string()).is_err());
+        databases.clone_database("c".to_string(), "d".to_string())?;
+        assert_eq!(databases.get_database_version("d"), Some(4));
+        databases.set("d".to_string(), 4, vec![kv(4, 4)], 1)?;
+        assert_eq!(value(&databases, "c", 4)?, None);
+        let databases = Databases::new(path.to_string(), 10000, HashSet::new(), true)?;
+        assert_eq!(databases.get_database_version("a"), None);
+        assert_eq!(value(&databases, "d", 4)?, Some(vec![4]));
+        assert_eq!(value(&databases, "b", 1)?, Some(vec![1]));
+        assert_eq!(value(&databases, "c", 1)?, Some(vec![2]));
+        assert_eq!(value(&databases, "c", 3)?, Some(vec![3]));
+        drop(databases);
+        // swap interrupted after the first rename is undone
+        fs::create_dir_all(base.join(".swap").join("c"))?;
+        fs::rename(base.join("b"), base.join(".swap").join("c").join("b"))?;
+        let databases = Databases::new(path.to_string(), 10000, HashSet::new(), true)?;
+        assert_eq!(value(&databases, "b", 1)?, Some(vec![1]));
+        assert_eq!(value(&databases, "c", 1)?, Some(vec![2]));
+        assert!(!fs::exists(base.join(".swap"))?);
+        drop(databases);
+        // swap interrupted after the second rename is completed
+        fs::create_dir_all(base.join(".swap").join("c"))?;
+        fs::rename(base.join("b"), base.join(".swap").join("c").join("b"))?;
+        fs::rename(base.join("c"), base.join("b"))?;
+        let databases = Databases::new(path.to_string(), 10000, HashSet::new(), true)?;
+        assert_eq!(value(&databases, "b", 1)?, Some(vec![2]));
+        assert_eq!(value(&databases, "c", 1)?, Some(vec![1]));
+        fs::remove_dir_all(path)
+    }
+}
@@ -4,8 +4,9 @@ mod configuration;
 mod command_processor;
 mod databases;
 mod database;
+mod byte_key_database;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{Error, ErrorKind};
 use smart_home_common::base_server::BaseServer;
 use smart_home_common::keys::read_key_file32;
@@ -18,6 +19,8 @@ pub struct UserWithKey {
     pub name: String,
     pub key: [u8; 32],
     pub databases: HashMap<String, bool>,
+    // databases with admin right (create/drop), admin right includes read write access
+    pub admin_databases: HashSet<String>,
 }
 
 impl UserWithKey {
@@ -33,12 +36,22 @@ impl UserWithKey {
                            format!("Database access error. User {} Database name {}", self.name, db_name)))
         }
     }
+
+    pub(crate) fn validate_admin_access(&self, db_name: &String) -> Result<(), Error> {
+        if self.admin_databases.contains(db_name) {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::InvalidInput,
+                           format!("Database admin access error. User {} Database name {}", self.name, db_name)))
+        }
+    }
 }
 
 impl UserWithKey {
     fn from(user: &User, key: [u8; 32]) -> UserWithKey {
         UserWithKey{id: user.id, name: user.name.clone(), key, 
-                    databases: user.databases.iter().map(|(k, v)|(k.clone(), v == "rw")).collect()}
+                    databases: user.databases.iter().map(|(k, v)|(k.clone(), v == "rw" || v == "admin")).collect(),
+                    admin_databases: user.databases.iter().filter(|(_, v)|*v == "admin").map(|(k, _)|k.clone()).collect()}
     }
 }
 
@@ -52,7 +65,9 @@ fn main() -> Result<(), Error> {
     }
     let message_processor =
         build_message_processor(UserCommandProcessor::new(config.base_folder.clone(),
-                                                          config.hash_divider, user_map)?, false)?;
+                                                          config.hash_divider,
+                                                          config.byte_key_databases.clone(),
+                                                          config.implicit_database_creation, user_map)?, false)?;
     let udp_server =
         Box::leak(Box::new(BaseServer::new(true, config.port_number,
                                            message_processor.clone(), 0,